@@ -0,0 +1,456 @@
+use axum::{
+    extract::{Query, State},
+    http::{
+        header::{AUTHORIZATION, CONTENT_TYPE},
+        HeaderMap, StatusCode,
+    },
+    response::{
+        sse::{self, KeepAlive, Sse},
+        Html, IntoResponse, Response,
+    },
+    routing::{get, post},
+    Json, Router,
+};
+use chrono::{DateTime, Duration, Local, NaiveDate, NaiveTime};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    convert::Infallible,
+    sync::Arc,
+};
+use tokio::{
+    sync::{broadcast, mpsc, mpsc::error::TrySendError, RwLock},
+    task::spawn_blocking,
+};
+use tokio_stream::{wrappers::BroadcastStream, Stream, StreamExt};
+
+use crate::{Actuator, Command, Config, History, Metrics, NetworkUpdate, StreamEvent};
+
+/// A status update and when it was sent
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct HistoryEntry {
+    pub time: DateTime<Local>,
+    #[serde(flatten)]
+    pub update: NetworkUpdate,
+}
+
+/// What the HTTP API serves, kept up to date by the controller
+pub struct Api {
+    config: Config,
+    /// The configuration file as JSON, without the key or broker credentials
+    source: serde_json::Value,
+    key: Option<String>,
+    history: VecDeque<HistoryEntry>,
+    commands: mpsc::Sender<Command>,
+    events: broadcast::Sender<StreamEvent>,
+    metrics: Metrics,
+    /// Readings, transitions and alarms kept across restarts
+    database: Option<Arc<History>>,
+}
+
+pub type SharedApi = Arc<RwLock<Api>>;
+
+impl Api {
+    /// Number of updates kept, a day's worth at the controller's 90 second cycle
+    const HISTORY_LENGTH: usize = 960;
+
+    /// Events buffered for each listener, a slow listener misses the ones past this
+    const EVENT_CAPACITY: usize = 64;
+
+    /// Set up the API for a config loaded from `source`, the text of the configuration file.
+    /// Overrides are sent to `commands`.
+    pub fn new(config: Config, source: &str, commands: mpsc::Sender<Command>) -> Self {
+        let mut source = toml::from_str::<toml::Table>(source).unwrap_or_default();
+        source.remove("auth");
+
+        if let Some(toml::Value::Table(mqtt)) = source.get_mut("mqtt") {
+            mqtt.remove("username");
+            mqtt.remove("password");
+        }
+
+        Self {
+            key: config.auth().map(|auth| auth.key.clone()),
+            config,
+            source: serde_json::to_value(source).unwrap_or_default(),
+            history: VecDeque::with_capacity(Self::HISTORY_LENGTH),
+            commands,
+            events: broadcast::channel(Self::EVENT_CAPACITY).0,
+            metrics: Metrics::default(),
+            database: None,
+        }
+    }
+
+    /// Serve readings, transitions and alarms from a history database
+    pub fn with_database(mut self, database: Arc<History>) -> Self {
+        self.database = Some(database);
+        self
+    }
+
+    pub fn database(&self) -> Option<&Arc<History>> {
+        self.database.as_ref()
+    }
+
+    /// Send an event to everyone listening
+    pub fn publish(&self, event: StreamEvent) {
+        // Nobody listening isn't an error
+        let _ = self.events.send(event);
+    }
+
+    /// Listen for events published from now on
+    pub fn subscribe(&self) -> broadcast::Receiver<StreamEvent> {
+        self.events.subscribe()
+    }
+
+    /// Record the update sent at a time
+    pub fn record(&mut self, time: DateTime<Local>, update: NetworkUpdate) {
+        if self.history.len() == Self::HISTORY_LENGTH {
+            self.history.pop_front();
+        }
+
+        self.history.push_back(HistoryEntry { time, update });
+    }
+
+    pub fn latest(&self) -> Option<&NetworkUpdate> {
+        self.history.back().map(|entry| &entry.update)
+    }
+
+    /// Updates sent since a time, or all of them, oldest first
+    pub fn history(&self, since: Option<&DateTime<Local>>) -> Vec<HistoryEntry> {
+        self.history
+            .iter()
+            .filter(|entry| since.is_none_or(|since| entry.time >= *since))
+            .cloned()
+            .collect()
+    }
+
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    pub fn metrics_mut(&mut self) -> &mut Metrics {
+        &mut self.metrics
+    }
+
+    /// The configuration file as JSON, without the key or broker credentials
+    pub fn source(&self) -> &serde_json::Value {
+        &self.source
+    }
+
+    /// The windows each actuator is scheduled to be on for on a date
+    pub fn schedule(&self, date: NaiveDate) -> BTreeMap<String, Vec<(NaiveTime, NaiveTime)>> {
+        Actuator::ALL
+            .iter()
+            .map(|actuator| (actuator.to_string(), self.config.windows(*actuator, date)))
+            .collect()
+    }
+
+    /// Whether the headers carry the key as a bearer token. Without a key, nobody is.
+    pub fn authorized(&self, headers: &HeaderMap) -> bool {
+        let Some(key) = &self.key else {
+            return false;
+        };
+
+        headers
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .is_some_and(|token| {
+                // Compare every byte so the time taken doesn't give away how much matched
+                token.len() == key.len()
+                    && token
+                        .bytes()
+                        .zip(key.bytes())
+                        .fold(0, |diff, (a, b)| diff | (a ^ b))
+                        == 0
+            })
+    }
+}
+
+#[derive(Serialize)]
+struct EnvironmentResponse {
+    temp: Option<f32>,
+    humidity: Option<f32>,
+    co2: Option<f32>,
+    soil: HashMap<String, f32>,
+}
+
+#[derive(Serialize)]
+struct ActuatorsResponse {
+    light_level: f64,
+    mist_on: bool,
+    fan_duty: f64,
+    fan_rpm: Vec<f64>,
+}
+
+#[derive(Deserialize)]
+struct HistoryQuery {
+    /// How far back to go, the whole history if not given
+    minutes: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct RangeQuery {
+    /// Metric to get readings of, the names of the metrics if not given
+    metric: Option<String>,
+    /// Start of the range, a day before its end if not given
+    from: Option<DateTime<Local>>,
+    /// End of the range, now if not given
+    to: Option<DateTime<Local>>,
+}
+
+impl RangeQuery {
+    /// The start and end of the range, or none if a day before its end is out of range
+    fn range(&self) -> Option<(DateTime<Local>, DateTime<Local>)> {
+        let to = self.to.unwrap_or_else(Local::now);
+        let from = match self.from {
+            Some(from) => from,
+            None => to.checked_sub_signed(Duration::days(1))?,
+        };
+
+        Some((from, to))
+    }
+}
+
+#[derive(Deserialize)]
+struct ScheduleQuery {
+    /// Date to get the schedule for, today if not given
+    date: Option<NaiveDate>,
+}
+
+#[derive(Deserialize)]
+struct OverrideRequest {
+    actuator: Actuator,
+    on: bool,
+    seconds: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct AutoRequest {
+    actuator: Actuator,
+}
+
+/// The dashboard page, with its styles and scripts inline so it works without internet access
+pub const DASHBOARD: &str = include_str!("dashboard.html");
+
+async fn dashboard() -> Html<&'static str> {
+    Html(DASHBOARD)
+}
+
+fn no_status() -> Response {
+    (StatusCode::SERVICE_UNAVAILABLE, "No status yet").into_response()
+}
+
+fn bad_range() -> Response {
+    (StatusCode::BAD_REQUEST, "Range is out of bounds").into_response()
+}
+
+async fn status(State(api): State<SharedApi>) -> Response {
+    match api.read().await.latest() {
+        Some(update) => Json(update.clone()).into_response(),
+        None => no_status(),
+    }
+}
+
+async fn environment(State(api): State<SharedApi>) -> Response {
+    match api.read().await.latest() {
+        Some(update) => Json(EnvironmentResponse {
+            temp: update.temp,
+            humidity: update.humidity,
+            co2: update.co2,
+            soil: update.soil.clone(),
+        })
+        .into_response(),
+        None => no_status(),
+    }
+}
+
+async fn actuators(State(api): State<SharedApi>) -> Response {
+    match api.read().await.latest() {
+        Some(update) => Json(ActuatorsResponse {
+            light_level: update.light_level,
+            mist_on: update.mist_on,
+            fan_duty: update.fan_duty,
+            fan_rpm: update.fan_rpm.clone(),
+        })
+        .into_response(),
+        None => no_status(),
+    }
+}
+
+async fn config(State(api): State<SharedApi>) -> Response {
+    Json(api.read().await.source().clone()).into_response()
+}
+
+async fn schedule(State(api): State<SharedApi>, Query(query): Query<ScheduleQuery>) -> Response {
+    let date = query.date.unwrap_or_else(|| Local::now().date_naive());
+    Json(api.read().await.schedule(date)).into_response()
+}
+
+async fn history(State(api): State<SharedApi>, Query(query): Query<HistoryQuery>) -> Response {
+    let since = match query.minutes {
+        Some(minutes) => match minutes
+            .checked_mul(60)
+            .and_then(|seconds| Duration::from_std(std::time::Duration::from_secs(seconds)).ok())
+            .and_then(|back| Local::now().checked_sub_signed(back))
+        {
+            Some(since) => Some(since),
+            None => return (StatusCode::BAD_REQUEST, "Too many minutes").into_response(),
+        },
+        None => None,
+    };
+
+    Json(api.read().await.history(since.as_ref())).into_response()
+}
+
+/// Run a query on the history database, if there is one, off the async threads
+async fn query<T: Serialize + Send + 'static>(
+    api: &SharedApi,
+    query: impl FnOnce(&History) -> anyhow::Result<T> + Send + 'static,
+) -> Response {
+    let Some(database) = api.read().await.database().cloned() else {
+        return (StatusCode::SERVICE_UNAVAILABLE, "No history database").into_response();
+    };
+
+    match spawn_blocking(move || query(&database)).await {
+        Ok(Ok(result)) => Json(result).into_response(),
+        Ok(Err(e)) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
+}
+
+async fn readings(State(api): State<SharedApi>, Query(range): Query<RangeQuery>) -> Response {
+    let Some((from, to)) = range.range() else {
+        return bad_range();
+    };
+
+    match range.metric {
+        Some(metric) => query(&api, move |database| database.samples(&metric, &from, &to)).await,
+        None => query(&api, |database| database.metrics()).await,
+    }
+}
+
+async fn transitions(State(api): State<SharedApi>, Query(range): Query<RangeQuery>) -> Response {
+    let Some((from, to)) = range.range() else {
+        return bad_range();
+    };
+    query(&api, move |database| database.transitions(&from, &to)).await
+}
+
+async fn alarms(State(api): State<SharedApi>, Query(range): Query<RangeQuery>) -> Response {
+    let Some((from, to)) = range.range() else {
+        return bad_range();
+    };
+    query(&api, move |database| database.alarms(&from, &to)).await
+}
+
+async fn metrics(State(api): State<SharedApi>) -> Response {
+    let api = api.read().await;
+
+    (
+        [(CONTENT_TYPE, "text/plain; version=0.0.4")],
+        api.metrics().render(api.latest(), Local::now()),
+    )
+        .into_response()
+}
+
+/// Stream events as they are published, skipping any a slow client falls too far behind on
+async fn events(
+    State(api): State<SharedApi>,
+) -> Sse<impl Stream<Item = Result<sse::Event, Infallible>>> {
+    let events = BroadcastStream::new(api.read().await.subscribe()).filter_map(|event| {
+        let event = event.ok()?;
+        sse::Event::default()
+            .event(event.name())
+            .json_data(&event)
+            .ok()
+            .map(Ok)
+    });
+
+    Sse::new(events).keep_alive(KeepAlive::default())
+}
+
+/// Pass a command on to the controller if the request carries the key
+async fn command(api: &SharedApi, headers: &HeaderMap, command: Command) -> Response {
+    let commands = {
+        let api = api.read().await;
+
+        if !api.authorized(headers) {
+            return (StatusCode::UNAUTHORIZED, "Missing or wrong key").into_response();
+        }
+
+        api.commands.clone()
+    };
+
+    // Don't wait on a controller that isn't taking commands, or hold up the API while it's busy
+    match commands.try_send(command) {
+        Ok(()) => StatusCode::ACCEPTED.into_response(),
+        Err(TrySendError::Full(_)) => (
+            StatusCode::SERVICE_UNAVAILABLE,
+            "Controller is busy, try again",
+        )
+            .into_response(),
+        Err(TrySendError::Closed(_)) => (
+            StatusCode::SERVICE_UNAVAILABLE,
+            "Controller is shutting down",
+        )
+            .into_response(),
+    }
+}
+
+async fn override_actuator(
+    State(api): State<SharedApi>,
+    headers: HeaderMap,
+    Json(request): Json<OverrideRequest>,
+) -> Response {
+    let OverrideRequest {
+        actuator,
+        on,
+        seconds,
+    } = request;
+
+    command(
+        &api,
+        &headers,
+        Command::Override {
+            actuator,
+            on,
+            seconds,
+        },
+    )
+    .await
+}
+
+async fn auto(
+    State(api): State<SharedApi>,
+    headers: HeaderMap,
+    Json(request): Json<AutoRequest>,
+) -> Response {
+    command(
+        &api,
+        &headers,
+        Command::Auto {
+            actuator: request.actuator,
+        },
+    )
+    .await
+}
+
+/// Routes of the HTTP API and the dashboard
+pub fn router(api: SharedApi) -> Router {
+    Router::new()
+        .route("/", get(dashboard))
+        .route("/api/status", get(status))
+        .route("/api/environment", get(environment))
+        .route("/api/actuators", get(actuators))
+        .route("/api/config", get(config))
+        .route("/api/schedule", get(schedule))
+        .route("/api/history", get(history))
+        .route("/api/events", get(events))
+        .route("/api/readings", get(readings))
+        .route("/api/transitions", get(transitions))
+        .route("/api/alarms", get(alarms))
+        .route("/api/override", post(override_actuator))
+        .route("/api/auto", post(auto))
+        .route("/metrics", get(metrics))
+        .with_state(api)
+}
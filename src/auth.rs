@@ -0,0 +1,105 @@
+use anyhow::{anyhow, ensure, Result};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::collections::HashMap;
+
+/// The shared secret controllers and monitors sign their packets with
+#[derive(Deserialize, Debug, Clone)]
+pub struct AuthConfig {
+    pub key: String,
+}
+
+/// A packet signed with the shared key
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Envelope {
+    /// Unix time in seconds the packet was sent at
+    pub timestamp: i64,
+    /// Random number used once, so a captured packet can't be sent again
+    pub nonce: u64,
+    /// The message as JSON
+    pub payload: String,
+    /// HMAC-SHA256 over the timestamp, nonce and payload, in hex
+    pub mac: String,
+}
+
+/// Seals packets in signed envelopes and opens the ones that are genuine and new
+#[derive(Debug, Clone)]
+pub struct Authenticator {
+    key: Vec<u8>,
+    /// Nonces seen recently, with their timestamps
+    seen: HashMap<u64, i64>,
+}
+
+impl Authenticator {
+    /// Seconds a packet's timestamp can be off from our clock, older packets are replays
+    pub const MAX_SKEW: i64 = 30;
+
+    pub fn new(key: &str) -> Self {
+        Self {
+            key: key.as_bytes().to_vec(),
+            seen: HashMap::new(),
+        }
+    }
+
+    fn mac(&self, timestamp: i64, nonce: u64, payload: &str) -> Hmac<Sha256> {
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(&self.key).expect("HMAC takes keys of any length");
+        mac.update(&timestamp.to_be_bytes());
+        mac.update(&nonce.to_be_bytes());
+        mac.update(payload.as_bytes());
+        mac
+    }
+
+    /// Seal a message sent at a time with a nonce
+    pub fn seal_at(&self, payload: &str, timestamp: i64, nonce: u64) -> Result<String> {
+        let mac = self.mac(timestamp, nonce, payload).finalize().into_bytes();
+
+        Ok(serde_json::to_string(&Envelope {
+            timestamp,
+            nonce,
+            payload: payload.to_string(),
+            mac: hex::encode(mac),
+        })?)
+    }
+
+    /// Seal a message sent now
+    pub fn seal(&self, payload: &str) -> Result<String> {
+        self.seal_at(payload, chrono::Utc::now().timestamp(), rand::random())
+    }
+
+    /// Open a packet received at a time, returning the message if the packet is signed with
+    /// our key, recent, and hasn't been seen before
+    pub fn open_at(&mut self, packet: &str, now: i64) -> Result<String> {
+        let envelope: Envelope = serde_json::from_str(packet)
+            .map_err(|e| anyhow!("Packet is not a signed envelope: {}", e))?;
+
+        self.mac(envelope.timestamp, envelope.nonce, &envelope.payload)
+            .verify_slice(&hex::decode(&envelope.mac)?)
+            .map_err(|_| anyhow!("Packet signature does not match"))?;
+
+        ensure!(
+            (now - envelope.timestamp).abs() <= Self::MAX_SKEW,
+            "Packet timestamp is {}s off",
+            now - envelope.timestamp
+        );
+
+        // Nonces only need remembering for as long as their packets pass the timestamp check
+        self.seen
+            .retain(|_, timestamp| (now - *timestamp).abs() <= Self::MAX_SKEW);
+
+        ensure!(
+            self.seen
+                .insert(envelope.nonce, envelope.timestamp)
+                .is_none(),
+            "Packet is a replay"
+        );
+
+        Ok(envelope.payload)
+    }
+
+    /// Open a packet received now
+    pub fn open(&mut self, packet: &str) -> Result<String> {
+        self.open_at(packet, chrono::Utc::now().timestamp())
+    }
+}
@@ -1,49 +1,96 @@
-use anyhow::{bail, Result};
+use anyhow::{anyhow, bail, Error, Result};
+use axum::Server;
 use chrono::{DateTime, Local};
 use clap::Parser;
 use dht22_pi::read as dht22_read;
-use grobot::{Config, Environment, Fan, Light, PORT, Mist};
+use grobot::{
+    api, Actuator, Ads1115, Alarm, Api, Authenticator, Co2Sensor, Command, Config, DimmableLight,
+    DliTracker, DoorState, Environment, Fan, History, Light, LightSensor, Mist, NetworkUpdate,
+    Override, Pump, PumpLedger, SensorHealth, Snapshot, Soil, State, StreamEvent, Switch,
+    Tachometer, Topics, COMMAND_PORT, FULL_LEVEL, HTTP_PORT, PORT,
+};
 use rppal::{
     gpio::Gpio,
     pwm::{Channel, Polarity, Pwm},
 };
+use rumqttc::{AsyncClient, Event, Packet, QoS};
+use serde_json::to_string;
 use std::{
-    net::{Ipv4Addr, SocketAddrV4},
+    collections::{HashMap, HashSet, VecDeque},
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
     path::PathBuf,
+    sync::Arc,
     time::Duration,
 };
 use tokio::{
+    fs::{create_dir_all, read_to_string, write},
     net::UdpSocket,
+    select,
     signal::ctrl_c,
     spawn,
     sync::{
-        broadcast::{channel as broadcast, Receiver, Sender},
+        broadcast::{
+            channel as broadcast,
+            error::{RecvError, TryRecvError},
+            Receiver, Sender,
+        },
+        mpsc,
         oneshot::channel as oneshot,
+        RwLock,
     },
-    time::sleep,
+    task::spawn_blocking,
+    time::{interval, interval_at, sleep, sleep_until, timeout, Instant, MissedTickBehavior},
 };
-use tracing::{error, info, subscriber::set_global_default, Level};
+use tracing::{error, info, subscriber::set_global_default, warn, Level};
 use tracing_appender::{non_blocking, rolling::daily};
 use tracing_subscriber::FmtSubscriber;
 
 // NF-F12 industialPPC Fan PWM Frequency
 const FAN_PWM_FREQUENCY: f64 = 25_000.0f64;
+// Fewest seconds to count tachometer pulses over, a shorter window can catch none at all
+const TACH_MIN_WINDOW: f32 = 10.0;
 // Pin for relay CH1
 const LIGHT_PIN: u8 = 26;
+// Number of seconds between light level updates while a dimmable light fades
+const LIGHT_FADE_INTERVAL: f32 = 5.0;
 // Pin for relay CH3
 const MIST_PIN: u8 = 21;
+// Number of seconds between checks of the door switch
+const DOOR_POLL_INTERVAL: f32 = 1.0;
+// Number of seconds between checks of the mister's reservoir switch
+const RESERVOIR_POLL_INTERVAL: f32 = 1.0;
 // Pin for temp/humidity sensor
 const SENSOR_PIN: u8 = 4;
 // Number of readings to take from the sensor before starting up
 const INITIAL_SENSOR_READINGS: u8 = 8;
-// Number of readings to take from the sensor each cycle
-const SENSOR_READINGS: u8 = 3;
-// Number of seconds to wait between sensor readings
+// Number of seconds to wait between initial sensor readings
 const SENSOR_READING_INTERVAL: f32 = 4.0;
-// Number of seconds to wait between time/sensor readings
+// Number of seconds between sensor readings once running
+const SENSOR_SAMPLE_INTERVAL: f32 = 30.0;
+// Number of seconds between broadcasts and environment decisions, schedule transitions are
+// acted on as they happen
 const MAINTHREAD_CYCLE_INTERVAL: f32 = 90.0;
 
 const BIND_ADDR: Ipv4Addr = Ipv4Addr::new(0, 0, 0, 0);
+// Number of commands from the HTTP API that can wait for the main thread
+const HTTP_COMMAND_QUEUE: usize = 8;
+// Commands from MQTT waiting for the main loop
+const MQTT_COMMAND_QUEUE: usize = 8;
+// Requests waiting for the MQTT event loop to send them
+const MQTT_REQUEST_CAPACITY: usize = 64;
+// Time to wait before reconnecting to the MQTT broker
+const MQTT_RECONNECT_DELAY: f32 = 5.0;
+// Seconds between averaging and dropping old history
+const HISTORY_PRUNE_INTERVAL: u64 = 3600;
+// Largest command we expect
+const COMMAND_BUFFER_SIZE: usize = 1024;
+// Where the kernel keeps the hostname, sent with each update
+const HOSTNAME_FILE: &str = "/proc/sys/kernel/hostname";
+// Where the controller state is saved between restarts
+const STATE_FILE: &str = "/var/lib/grobot/state.json";
+// Number of messages the bus holds for a task before it starts missing them. The main thread
+// only drains status messages once a cycle, and a light fade sends a level every step.
+const BUS_CAPACITY: usize = 64;
 
 #[derive(Parser)]
 struct Args {
@@ -59,21 +106,66 @@ struct Args {
     #[clap(short = 'L', long, default_value_t = BIND_ADDR)]
     // Listen address
     listen_addr: Ipv4Addr,
+    #[clap(short, long, default_value_t = COMMAND_PORT)]
+    /// Port to listen for commands on
+    command_port: u16,
+    #[clap(short = 'H', long, default_value_t = HTTP_PORT)]
+    /// Port to serve the HTTP API on
+    http_port: u16,
+    #[clap(short, long, default_value = STATE_FILE)]
+    /// Path to save state to, so the controller picks up where it left off after a restart
+    state_file: PathBuf,
 }
 
 #[derive(Clone, Debug)]
 enum Message {
     /// Setup Info
-    Setup(Config),
+    Setup(Box<Config>),
+    /// State saved before a restart
+    Restore(Box<State>),
     /// Local time
     Time(DateTime<Local>),
     /// Temp and humidity
     Environment((f32, f32)),
+    /// The latest raw temperature (F) and humidity reading
+    Reading((f32, f32)),
+    /// CO2 in ppm
+    Co2(f32),
+    /// Soil moisture in percent for each pot
+    Soil(HashMap<String, f32>),
+    /// Time to step a light fade along
+    Fade,
+    /// Time to check the mister's reservoir
+    CheckReservoir,
+    /// Light level in percent, sent when it changes
+    LightLevel(f64),
+    /// An on/off actuator was switched
+    ActuatorState(Actuator, bool),
+    /// An actuator was overridden by a remote command, or handed back to automatic if `None`
+    Override(Actuator, Option<Override>),
+    /// Daily light integral so far today
+    Dli(DliTracker),
+    /// The pump dispensed a dose
+    Pumped(PumpLedger, HashMap<String, DateTime<Local>>),
+    /// Fan speeds in RPM
+    FanSpeed(Vec<f64>),
+    /// A sensor failed to give a reading
+    ReadFailed(&'static str),
+    /// Something is wrong
+    Alarm(Alarm),
+    /// Something that was wrong is fixed
+    AlarmCleared(Alarm),
+    /// The cabinet door was opened
+    DoorOpened,
+    /// The cabinet door was closed at a time
+    DoorClosed(DateTime<Local>),
+    /// The status update sent out this cycle
+    Status(Box<NetworkUpdate>),
     /// Stop now
     Exit,
 }
 
-async fn light(mut rx: Receiver<Message>) -> Result<()> {
+async fn light(mut rx: Receiver<Message>, tx: Sender<Message>) -> Result<()> {
     let gpio = Gpio::new()?;
     let light_pin = gpio.get(LIGHT_PIN)?;
     let mut light = Light::new(light_pin.into_output());
@@ -89,11 +181,71 @@ async fn light(mut rx: Receiver<Message>) -> Result<()> {
         bail!("Light thread did not receive setup message");
     };
 
+    // Start up the dimmer at 0% power if the light has one
+    let mut dimmer = match config.light_dimming() {
+        Some(dimming) => Some(DimmableLight::new(Pwm::with_frequency(
+            Channel::Pwm1,
+            dimming.frequency,
+            0.0,
+            if dimming.invert {
+                Polarity::Inverse
+            } else {
+                Polarity::Normal
+            },
+            true,
+        )?)),
+        None => None,
+    };
+
+    // Measure the light if there is a sensor, otherwise it is estimated from the light level
+    let mut sensor = match config.light_dli().and_then(|dli| dli.sensor.as_ref()) {
+        Some(sensor) => Some(LightSensor::new(sensor)?),
+        None => None,
+    };
+
+    let mut tracker = DliTracker::default();
+    let mut door = DoorState::default();
+    let mut last_level = None;
+    let mut manual = None;
     let mut last_time = None;
     let mut last_env = None;
 
     loop {
-        match rx.recv().await? {
+        let message = if dimmer.is_some() {
+            // Wake up periodically to step any fades along
+            select! {
+                message = rx.recv() => message?,
+                _ = sleep(Duration::from_secs_f32(LIGHT_FADE_INTERVAL)) => {
+                    if last_time.is_some() {
+                        last_time = Some(Local::now());
+                    }
+                    Message::Fade
+                }
+            }
+        } else {
+            rx.recv().await?
+        };
+
+        let tick = matches!(message, Message::Time(_));
+
+        match message {
+            Message::Restore(state) => {
+                info!("Light thread restoring light level {}%", state.light_level);
+                tracker = state.dli;
+                tracker.resume();
+
+                if state.light_level > 0.0 {
+                    light.on();
+                }
+
+                if let Some(dimmer) = dimmer.as_mut() {
+                    dimmer.set_level(state.light_level)?;
+                }
+
+                // Let the other tasks know, they start out thinking it's off
+                last_level = Some(state.light_level);
+                tx.send(Message::LightLevel(state.light_level))?;
+            }
             Message::Time(time) => {
                 info!("Light thread received time update with time {:?}", time);
                 last_time = Some(time);
@@ -106,6 +258,18 @@ async fn light(mut rx: Receiver<Message>) -> Result<()> {
                 );
                 last_env = Some((temp, humidity));
             }
+            Message::Override(Actuator::Light, held) => {
+                info!("Light thread received override {:?}", held);
+                manual = held;
+            }
+            Message::DoorOpened => {
+                info!("Light thread pausing environment decisions, door opened");
+                door.open();
+            }
+            Message::DoorClosed(time) => {
+                info!("Light thread received door closed at {:?}", time);
+                door.close(time);
+            }
             Message::Exit => {
                 // Exit the loop and the thread
                 info!("Received exit message on light thread, exiting");
@@ -117,14 +281,87 @@ async fn light(mut rx: Receiver<Message>) -> Result<()> {
         }
 
         if let Some(time) = last_time {
-            if let Some((temp, humidity)) = last_env {
-                if config.light_on(&time, (temp, humidity)) {
-                    info!("Light thread turning light on");
-                    light.on();
+            // Readings are off while the door is open, and there are none if the sensor failed
+            // from the start, so then only follow the schedule
+            let environment = last_env.filter(|_| !door.suspended(config.door_settle(), &time));
+            let held = manual.filter(|held: &Override| held.active(&Local::now()));
+
+            let mut level = if door.is_open() && config.door_pauses(Actuator::Light) {
+                info!("Light thread pausing light while the door is open");
+                0.0
+            } else if let Some(held) = held {
+                info!("Light thread holding light on: {}", held.on);
+                if held.on {
+                    FULL_LEVEL
                 } else {
-                    info!("Light thread turning light off");
-                    light.off();
+                    0.0
                 }
+            } else if let Some(environment) = environment {
+                if dimmer.is_some() {
+                    config.light_level(&time, environment)
+                } else if config.light_on(&time, environment) {
+                    FULL_LEVEL
+                } else {
+                    0.0
+                }
+            } else if dimmer.is_some() {
+                config.light_level_schedule(&time)
+            } else if config.light_on_schedule(&time) {
+                FULL_LEVEL
+            } else {
+                0.0
+            };
+
+            if held.is_none()
+                && environment.is_some_and(|environment| {
+                    config.light_compensate(&time, environment, tracker.dli())
+                })
+            {
+                info!("Light thread extending light to reach target DLI");
+                level = FULL_LEVEL;
+            }
+
+            if level > 0.0 {
+                info!("Light thread turning light on");
+                light.on();
+            } else {
+                info!("Light thread turning light off");
+                light.off();
+            }
+
+            if let Some(dimmer) = dimmer.as_mut() {
+                info!("Light thread setting light level to {}%", level);
+                dimmer.set_level(level)?;
+            }
+
+            if last_level != Some(level) {
+                last_level = Some(level);
+                tx.send(Message::LightLevel(level))?;
+            }
+
+            if let Some(dli) = config.light_dli() {
+                let estimate = dli.ppfd * level / FULL_LEVEL;
+
+                let ppfd = match sensor.as_mut().map(|s| s.ppfd()) {
+                    Some(Ok(ppfd)) => ppfd,
+                    Some(Err(e)) => {
+                        warn!("Failed to read from light sensor: {}", e);
+                        estimate
+                    }
+                    None => estimate,
+                };
+
+                tracker.update(time, ppfd);
+
+                if tick {
+                    tx.send(Message::Dli(tracker.clone()))?;
+                }
+
+                info!(
+                    "Light thread PPFD is {} umol/m^2/s, DLI so far today is {} mol/m^2/day",
+                    ppfd,
+                    tracker.dli()
+                );
             }
         }
     }
@@ -132,7 +369,7 @@ async fn light(mut rx: Receiver<Message>) -> Result<()> {
     Ok(())
 }
 
-async fn mist(mut rx: Receiver<Message>) -> Result<()> {
+async fn mist(mut rx: Receiver<Message>, tx: Sender<Message>) -> Result<()> {
     let gpio = Gpio::new()?;
     let mist_pin = gpio.get(MIST_PIN)?;
     let mut mist = Mist::new(mist_pin.into_output());
@@ -148,11 +385,46 @@ async fn mist(mut rx: Receiver<Message>) -> Result<()> {
         bail!("Mist thread did not receive setup message");
     };
 
+    let reservoir = match config.mist_reservoir() {
+        Some(reservoir) => Some(Switch::new(
+            gpio.get(reservoir.pin)?.into_input_pullup(),
+            reservoir.active,
+        )),
+        None => None,
+    };
+
+    let mut reservoir_low = false;
+    let mut last_on = None;
+    let mut manual = None;
+    let mut door = DoorState::default();
     let mut last_time = None;
     let mut last_env = None;
 
     loop {
-        match rx.recv().await? {
+        let message = if reservoir.is_some() {
+            // Check the reservoir between messages too, so a running mister stops as soon as
+            // it is low rather than at the next update
+            select! {
+                message = rx.recv() => message?,
+                _ = sleep(Duration::from_secs_f32(RESERVOIR_POLL_INTERVAL)) => Message::CheckReservoir,
+            }
+        } else {
+            rx.recv().await?
+        };
+
+        let poll = matches!(message, Message::CheckReservoir);
+        let mut refilled = false;
+
+        match message {
+            Message::Restore(state) => {
+                info!("Mist thread restoring mist on: {}", state.mist_on);
+
+                if state.mist_on {
+                    mist.on();
+                }
+
+                report(&tx, Actuator::Mist, state.mist_on, &mut last_on)?;
+            }
             Message::Time(time) => {
                 info!("Mist thread received time update with time {:?}", time);
                 last_time = Some(time);
@@ -165,6 +437,18 @@ async fn mist(mut rx: Receiver<Message>) -> Result<()> {
                 );
                 last_env = Some((temp, humidity));
             }
+            Message::Override(Actuator::Mist, held) => {
+                info!("Mist thread received override {:?}", held);
+                manual = held;
+            }
+            Message::DoorOpened => {
+                info!("Mist thread pausing environment decisions, door opened");
+                door.open();
+            }
+            Message::DoorClosed(time) => {
+                info!("Mist thread received door closed at {:?}", time);
+                door.close(time);
+            }
             Message::Exit => {
                 // Exit the loop and the thread
                 info!("Received exit message on mist thread, exiting");
@@ -175,23 +459,67 @@ async fn mist(mut rx: Receiver<Message>) -> Result<()> {
             }
         }
 
-        if let Some(time) = last_time {
-            if let Some((temp, humidity)) = last_env {
-                if config.mist_on(&time, (temp, humidity)) {
-                    info!("Mist thread turning light on");
-                    mist.on();
+        // The mister burns out if it runs dry, so a low reservoir overrides everything else
+        if let Some(reservoir) = reservoir.as_ref() {
+            if reservoir.is_active() != reservoir_low {
+                reservoir_low = reservoir.is_active();
+
+                if reservoir_low {
+                    error!("Mist thread reservoir is low, locking out mister");
+                    tx.send(Message::Alarm(Alarm::ReservoirLow))?;
                 } else {
-                    info!("Mist thread turning light off");
-                    mist.off();
+                    info!("Mist thread reservoir refilled");
+                    tx.send(Message::AlarmCleared(Alarm::ReservoirLow))?;
+                    refilled = true;
                 }
             }
         }
+
+        if reservoir_low {
+            mist.off();
+            report(&tx, Actuator::Mist, false, &mut last_on)?;
+            continue;
+        }
+
+        // Nothing else changed between messages, unless the mister can run again
+        if poll && !refilled {
+            continue;
+        }
+
+        if let Some(time) = last_time {
+            // Readings are off while the door is open, and there are none if the sensor failed
+            // from the start, so then only follow the schedule
+            let environment = last_env.filter(|_| !door.suspended(config.door_settle(), &time));
+            let held = manual.filter(|held: &Override| held.active(&Local::now()));
+
+            let on = if door.is_open() && config.door_pauses(Actuator::Mist) {
+                info!("Mist thread pausing mist while the door is open");
+                false
+            } else if let Some(held) = held {
+                info!("Mist thread holding mist on: {}", held.on);
+                held.on
+            } else if let Some((temp, humidity)) = environment {
+                config.mist_on(&time, (temp, humidity))
+            } else {
+                config.mist_on_schedule(&time)
+            };
+
+            if on {
+                info!("Mist thread turning light on");
+                mist.on();
+            } else {
+                info!("Mist thread turning light off");
+                mist.off();
+            }
+
+            report(&tx, Actuator::Mist, on, &mut last_on)?;
+        }
     }
 
     Ok(())
 }
 
-async fn fan(mut rx: Receiver<Message>) -> Result<()> {
+async fn fan(mut rx: Receiver<Message>, tx: Sender<Message>) -> Result<()> {
     // Start up the fan at 0% power
     let fan_pwm = Pwm::with_frequency(
         Channel::Pwm0,
@@ -208,16 +536,75 @@ async fn fan(mut rx: Receiver<Message>) -> Result<()> {
         bail!("Fan thread did not receive setup message");
     };
 
+    let gpio = Gpio::new()?;
+    let mut tachometers = Vec::new();
+
+    for pin in config.fan_tach_pins() {
+        tachometers.push(Tachometer::new(gpio.get(*pin)?.into_input_pullup())?);
+    }
+
     let mut fan = Fan::new(fan_pwm, config.fan_power());
+    let mut measured = Instant::now();
+    let mut duty_cycle = 0.0;
+    let mut duty_cycle_changed = false;
+    let mut stalled = HashSet::new();
+    let mut last_co2 = None;
+    let mut manual = None;
+    let mut door = DoorState::default();
     let mut last_time = None;
     let mut last_env = None;
 
     loop {
         match rx.recv().await? {
+            Message::Restore(state) => {
+                info!("Fan thread restoring fan on: {}", state.fan_on);
+
+                if state.fan_on {
+                    fan.on()?;
+                    duty_cycle = config.fan_power().as_duty_cycle();
+                    // The next decision sees no change, so let the other tasks know now
+                    tx.send(Message::ActuatorState(Actuator::Fan, true))?;
+                }
+            }
             Message::Time(time) => {
                 // Run fans for 10 mins at the top of the hour
                 info!("Fan thread received time update with time {:?}", time);
                 last_time = Some(time);
+
+                // Time updates can come moments apart, so keep counting until the window is long
+                // enough to measure over
+                if !tachometers.is_empty()
+                    && measured.elapsed() >= Duration::from_secs_f32(TACH_MIN_WINDOW)
+                {
+                    measured = Instant::now();
+                    let rpm = tachometers.iter_mut().map(|t| t.rpm()).collect::<Vec<_>>();
+                    info!("Fan thread measured fan speeds {:?} RPM", rpm);
+
+                    // Only check for stalls if the fan was driven at the same duty cycle for
+                    // the whole time the speed was measured over
+                    if !duty_cycle_changed {
+                        for (fan, rpm) in rpm.iter().enumerate() {
+                            let alarm = Alarm::FanStalled { fan };
+
+                            if config.fan_stalled(duty_cycle, *rpm) {
+                                if stalled.insert(fan) {
+                                    error!(
+                                        "Fan {} stalled at {}% duty cycle",
+                                        fan,
+                                        duty_cycle * 100.0
+                                    );
+                                    tx.send(Message::Alarm(alarm))?;
+                                }
+                            } else if stalled.remove(&fan) {
+                                info!("Fan {} is spinning again at {} RPM", fan, rpm);
+                                tx.send(Message::AlarmCleared(alarm))?;
+                            }
+                        }
+                    }
+
+                    duty_cycle_changed = false;
+                    tx.send(Message::FanSpeed(rpm))?;
+                }
             }
             Message::Environment((temp, humidity)) => {
                 // Do something with env
@@ -228,6 +615,22 @@ async fn fan(mut rx: Receiver<Message>) -> Result<()> {
 
                 last_env = Some((temp, humidity));
             }
+            Message::Override(Actuator::Fan, held) => {
+                info!("Fan thread received override {:?}", held);
+                manual = held;
+            }
+            Message::DoorOpened => {
+                info!("Fan thread pausing environment decisions, door opened");
+                door.open();
+            }
+            Message::DoorClosed(time) => {
+                info!("Fan thread received door closed at {:?}", time);
+                door.close(time);
+            }
+            Message::Co2(co2) => {
+                info!("Fan thread received CO2 update with {}ppm", co2);
+                last_co2 = Some(co2);
+            }
             Message::Exit => {
                 info!("Received exit message on fan thread, exiting");
                 break;
@@ -236,13 +639,565 @@ async fn fan(mut rx: Receiver<Message>) -> Result<()> {
         }
 
         if let Some(time) = last_time {
-            if let Some((temp, humidity)) = last_env {
-                if config.fan_on(&time, (temp, humidity)) {
-                    info!("Fan thread turning fan on");
-                    fan.on()?;
+            // Readings are off while the door is open, and there are none if the sensor failed
+            // from the start, so then only follow the schedule
+            let environment = last_env.filter(|_| !door.suspended(config.door_settle(), &time));
+            let held = manual.filter(|held: &Override| held.active(&Local::now()));
+
+            let on = if door.is_open() && config.door_pauses(Actuator::Fan) {
+                info!("Fan thread pausing fan while the door is open");
+                false
+            } else if let Some(held) = held {
+                info!("Fan thread holding fan on: {}", held.on);
+                held.on
+            } else if let Some((temp, humidity)) = environment {
+                config.fan_on_with_co2(&time, (temp, humidity), last_co2)
+            } else {
+                config.fan_on_schedule(&time)
+            };
+
+            let new_duty_cycle = if on {
+                info!("Fan thread turning fan on");
+                fan.on()?;
+                config.fan_power().as_duty_cycle()
+            } else {
+                info!("Fan thread turning fan off");
+                fan.off()?;
+                0.0
+            };
+
+            if new_duty_cycle != duty_cycle {
+                duty_cycle_changed = true;
+                tx.send(Message::ActuatorState(Actuator::Fan, on))?;
+            }
+
+            duty_cycle = new_duty_cycle;
+        }
+    }
+
+    Ok(())
+}
+
+async fn pump(mut rx: Receiver<Message>, tx: Sender<Message>) -> Result<()> {
+    let config = if let Message::Setup(config) = rx.recv().await? {
+        info!(
+            "Pump thread received setup message with config {:?}",
+            config
+        );
+        config
+    } else {
+        bail!("Pump thread did not receive setup message");
+    };
+
+    let Some(pump_config) = config.pump() else {
+        info!("No pump configured, exiting pump thread");
+        return Ok(());
+    };
+
+    let gpio = Gpio::new()?;
+    let mut pump = Pump::new(gpio.get(pump_config.pin)?.into_output());
+    pump.off();
+
+    let mut ledger = PumpLedger::default();
+    let mut doses = VecDeque::new();
+    let mut last_watered = HashMap::new();
+    let mut last_time = None;
+    let mut dosing_until = None;
+    // The pot the running dose is for, if it was called for by a moisture rule
+    let mut dosing_pot = None;
+
+    loop {
+        select! {
+            message = rx.recv() => match message? {
+                Message::Restore(state) => {
+                    info!("Pump thread restoring {}ml dispensed today", state.pump.dispensed());
+                    ledger = state.pump;
+                    last_watered = state.last_watered;
+                }
+                Message::Time(time) => {
+                    info!("Pump thread received time update with time {:?}", time);
+
+                    if let Some(last_time) = last_time {
+                        doses.extend(
+                            config
+                                .pump_doses_due(&last_time, &time)
+                                .into_iter()
+                                .map(|volume| (None, volume)),
+                        );
+                    }
+
+                    last_time = Some(time);
+                }
+                Message::Soil(moisture) => {
+                    info!("Pump thread received soil moisture update {:?}", moisture);
+                    let now = Local::now();
+
+                    for (pot, volume) in pump_config.rule_doses(&moisture, &last_watered, &now) {
+                        // The readings lag behind a dose, so don't pile another on top
+                        if dosing_pot.as_ref() == Some(&pot)
+                            || doses.iter().any(|(queued, _)| queued.as_ref() == Some(&pot))
+                        {
+                            continue;
+                        }
+
+                        info!("Pump thread watering {}, the soil is too dry", pot);
+                        last_watered.insert(pot.clone(), now);
+                        doses.push_back((Some(pot), volume));
+                    }
+                }
+                Message::Exit => {
+                    info!("Received exit message on pump thread, exiting");
+                    pump.off();
+                    break;
+                }
+                _ => {}
+            },
+            _ = sleep_until(dosing_until.unwrap_or_else(Instant::now)), if dosing_until.is_some() => {
+                info!("Pump thread finished dose");
+                pump.off();
+                dosing_until = None;
+                dosing_pot = None;
+            }
+        }
+
+        // Only one dose runs at a time, the rest wait their turn
+        if dosing_until.is_none() {
+            if let Some((pot, volume)) = doses.pop_front() {
+                let volume =
+                    ledger.dispense(Local::now().date_naive(), volume, pump_config.max_daily);
+
+                if volume > 0.0 {
+                    info!(
+                        "Pump thread dispensing {}ml, {}ml dispensed today",
+                        volume,
+                        ledger.dispensed()
+                    );
+                    pump.on();
+                    dosing_until = Some(Instant::now() + pump_config.run_time(volume));
+                    dosing_pot = pot;
+                    tx.send(Message::Pumped(ledger.clone(), last_watered.clone()))?;
                 } else {
-                    info!("Fan thread turning fan off");
-                    fan.off()?;
+                    warn!("Pump thread skipping dose, daily maximum reached");
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+async fn camera(mut rx: Receiver<Message>) -> Result<()> {
+    let config = if let Message::Setup(config) = rx.recv().await? {
+        info!(
+            "Camera thread received setup message with config {:?}",
+            config
+        );
+        config
+    } else {
+        bail!("Camera thread did not receive setup message");
+    };
+
+    let Some(camera) = config.camera() else {
+        info!("No camera configured, exiting camera thread");
+        return Ok(());
+    };
+
+    let mut capture = interval(camera.interval.to_std()?);
+    let mut light_level = 0.0;
+    let mut last_env = None;
+    let mut last_co2 = None;
+
+    loop {
+        select! {
+            message = rx.recv() => match message? {
+                Message::LightLevel(level) => {
+                    info!("Camera thread received light level {}%", level);
+                    light_level = level;
+                }
+                Message::Environment(environment) => last_env = Some(environment),
+                Message::Co2(co2) => last_co2 = Some(co2),
+                Message::Exit => {
+                    info!("Received exit message on camera thread, exiting");
+                    break;
+                }
+                _ => {}
+            },
+            _ = capture.tick() => {
+                // Stills in the dark are just black
+                if light_level <= 0.0 {
+                    continue;
+                }
+
+                let time = Local::now();
+                let path = camera.image_path(&time);
+                info!("Camera thread taking still {:?}", path);
+
+                // A full or missing disk may come right again, so only this still is lost
+                if let Some(dir) = path.parent() {
+                    if let Err(e) = create_dir_all(dir).await {
+                        warn!("Failed to create directory {:?} for still: {}", dir, e);
+                        continue;
+                    }
+                }
+
+                if let Err(e) = camera.capture(&path).await {
+                    warn!("Failed to take still: {}", e);
+                    continue;
+                }
+
+                let snapshot = Snapshot {
+                    time,
+                    temp: last_env.map(|(temp, _)| temp),
+                    humidity: last_env.map(|(_, humidity)| humidity),
+                    co2: last_co2,
+                    light_level,
+                };
+
+                if let Err(e) = write(path.with_extension("json"), to_string(&snapshot)?).await {
+                    warn!("Failed to write still metadata: {}", e);
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// The event streamed to HTTP clients for a message on the bus, if it is one they care about
+fn stream_event(message: &Message) -> Option<StreamEvent> {
+    Some(match message {
+        Message::Environment((temp, humidity)) => StreamEvent::Environment {
+            temp: *temp,
+            humidity: *humidity,
+        },
+        Message::Co2(ppm) => StreamEvent::Co2 { ppm: *ppm },
+        Message::Soil(moisture) => StreamEvent::Soil {
+            moisture: moisture.clone(),
+        },
+        Message::LightLevel(level) => StreamEvent::Light { level: *level },
+        Message::ActuatorState(actuator, on) => StreamEvent::Actuator {
+            actuator: *actuator,
+            on: *on,
+        },
+        Message::Override(actuator, held) => StreamEvent::Override {
+            actuator: *actuator,
+            held: *held,
+        },
+        Message::FanSpeed(rpm) => StreamEvent::FanSpeed { rpm: rpm.clone() },
+        Message::Alarm(alarm) => StreamEvent::Alarm {
+            alarm: alarm.clone(),
+        },
+        Message::AlarmCleared(alarm) => StreamEvent::AlarmCleared {
+            alarm: alarm.clone(),
+        },
+        Message::DoorOpened => StreamEvent::DoorOpened,
+        Message::DoorClosed(time) => StreamEvent::DoorClosed { time: *time },
+        Message::Status(update) => StreamEvent::Status(update.clone()),
+        _ => return None,
+    })
+}
+
+async fn http(
+    mut rx: Receiver<Message>,
+    commands: mpsc::Sender<Command>,
+    addr: SocketAddr,
+    source: String,
+    database: Option<Arc<History>>,
+) -> Result<()> {
+    let config = if let Message::Setup(config) = rx.recv().await? {
+        info!(
+            "HTTP thread received setup message with config {:?}",
+            config
+        );
+        config
+    } else {
+        bail!("HTTP thread did not receive setup message");
+    };
+
+    let mut api = Api::new(*config, &source, commands);
+
+    if let Some(database) = database {
+        api = api.with_database(database);
+    }
+
+    let api = Arc::new(RwLock::new(api));
+    let server = Server::try_bind(&addr)?.serve(api::router(api.clone()).into_make_service());
+
+    info!("Serving HTTP API on {}", addr);
+
+    spawn(async move {
+        if let Err(e) = server.await {
+            error!("HTTP server stopped: {}", e);
+        }
+    });
+
+    loop {
+        let message = rx.recv().await?;
+
+        if let Some(event) = stream_event(&message) {
+            api.read().await.publish(event);
+        }
+
+        match message {
+            Message::Status(update) => api.write().await.record(Local::now(), *update),
+            Message::Reading((temp, humidity)) => {
+                api.write().await.metrics_mut().reading(temp, humidity)
+            }
+            Message::ReadFailed(sensor) => api.write().await.metrics_mut().read_failed(sensor),
+            Message::ActuatorState(actuator, on) => {
+                api.write()
+                    .await
+                    .metrics_mut()
+                    .switched(actuator, on, Local::now())
+            }
+            Message::LightLevel(level) => {
+                api.write()
+                    .await
+                    .metrics_mut()
+                    .switched(Actuator::Light, level > 0.0, Local::now())
+            }
+            Message::Exit => {
+                info!("Received exit message on HTTP thread, exiting");
+                break;
+            }
+            _ => {}
+        }
+    }
+
+    Ok(())
+}
+
+async fn hostname() -> String {
+    read_to_string(HOSTNAME_FILE)
+        .await
+        .map(|hostname| hostname.trim().to_string())
+        .unwrap_or_else(|_| "unknown".to_string())
+}
+
+/// Store what a message on the bus says in the history database
+fn record(history: &History, message: &Message, now: &DateTime<Local>) -> Result<()> {
+    match message {
+        Message::Setup(config) => history.record_thresholds(now, config.thresholds())?,
+        Message::Status(update) => history.record_thresholds(now, &update.thresholds)?,
+        Message::Environment((temp, humidity)) => {
+            history.record(now, "temperature", *temp as f64)?;
+            history.record(now, "humidity", *humidity as f64)?;
+        }
+        Message::Reading((temp, humidity)) => {
+            history.record(now, "raw_temperature", *temp as f64)?;
+            history.record(now, "raw_humidity", *humidity as f64)?;
+        }
+        Message::Co2(ppm) => history.record(now, "co2", *ppm as f64)?,
+        Message::Soil(moisture) => {
+            for (pot, moisture) in moisture {
+                history.record(now, &format!("soil/{}", pot), *moisture as f64)?;
+            }
+        }
+        Message::LightLevel(level) => history.record(now, "light_level", *level)?,
+        Message::FanSpeed(rpm) => {
+            for (fan, rpm) in rpm.iter().enumerate() {
+                history.record(now, &format!("fan_rpm/{}", fan), *rpm)?;
+            }
+        }
+        Message::ActuatorState(actuator, on) => history.record_transition(now, *actuator, *on)?,
+        Message::Alarm(alarm) => history.record_alarm(now, alarm, true)?,
+        Message::AlarmCleared(alarm) => history.record_alarm(now, alarm, false)?,
+        _ => {}
+    }
+
+    Ok(())
+}
+
+async fn history(mut rx: Receiver<Message>, history: Arc<History>) -> Result<()> {
+    let mut pruned: Option<Instant> = None;
+    let mut light_on = None;
+
+    loop {
+        let message = match rx.recv().await {
+            Ok(Message::Exit) => {
+                info!("Received exit message on history thread, exiting");
+                break;
+            }
+            Ok(message) => message,
+            Err(RecvError::Lagged(missed)) => {
+                warn!("History thread missed {} messages", missed);
+                continue;
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        let now = Local::now();
+        let prune = pruned
+            .is_none_or(|pruned| pruned.elapsed() >= Duration::from_secs(HISTORY_PRUNE_INTERVAL));
+        let history = history.clone();
+
+        // The light only reports its level, so it switched when that crosses zero
+        let light_switched = match message {
+            Message::LightLevel(level) if light_on != Some(level > 0.0) => {
+                light_on = Some(level > 0.0);
+                light_on
+            }
+            _ => None,
+        };
+
+        // SQLite blocks, so keep it off the async threads
+        let result = spawn_blocking(move || {
+            record(&history, &message, &now)?;
+
+            if let Some(on) = light_switched {
+                history.record_transition(&now, Actuator::Light, on)?;
+            }
+
+            if prune {
+                history.prune(&now)?;
+            }
+
+            Ok::<_, Error>(())
+        })
+        .await?;
+
+        if let Err(e) = result {
+            warn!("Failed to record history: {}", e);
+        }
+
+        if prune {
+            pruned = Some(Instant::now());
+        }
+    }
+
+    Ok(())
+}
+
+/// Publish an MQTT message without waiting, as the event loop only drains the queue between
+/// our own messages
+fn publish(client: &AsyncClient, topic: String, payload: String) {
+    if let Err(e) = client.try_publish(&topic, QoS::AtLeastOnce, true, payload) {
+        warn!("Failed to publish to {}: {}", topic, e);
+    }
+}
+
+async fn mqtt(mut rx: Receiver<Message>, commands: mpsc::Sender<Command>) -> Result<()> {
+    let config = if let Message::Setup(config) = rx.recv().await? {
+        info!(
+            "MQTT thread received setup message with config {:?}",
+            config
+        );
+        config
+    } else {
+        bail!("MQTT thread did not receive setup message");
+    };
+
+    let Some(mqtt) = config.mqtt() else {
+        info!("No MQTT broker configured, exiting MQTT thread");
+        return Ok(());
+    };
+
+    let topics = Topics::new(mqtt, &hostname().await);
+    let (client, mut events) = AsyncClient::new(mqtt.options(&topics), MQTT_REQUEST_CAPACITY);
+
+    loop {
+        select! {
+            message = rx.recv() => {
+                let message = match message {
+                    Ok(message) => message,
+                    // Waiting out a reconnect can leave us behind, newer states will follow
+                    Err(RecvError::Lagged(missed)) => {
+                        warn!("MQTT thread missed {} messages", missed);
+                        continue;
+                    }
+                    Err(e) => return Err(e.into()),
+                };
+
+                if let Message::Exit = message {
+                    info!("Received exit message on MQTT thread, exiting");
+                    break;
+                }
+
+                if let Some(event) = stream_event(&message) {
+                    for (topic, payload) in topics.publications(&event) {
+                        publish(&client, topic, payload);
+                    }
+                }
+            }
+            event = events.poll() => match event {
+                Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                    info!("Connected to MQTT broker {}:{}", mqtt.host, mqtt.port);
+
+                    for (topic, payload) in topics.discovery(&config) {
+                        publish(&client, topic, payload);
+                    }
+                    publish(&client, topics.availability(), Topics::ONLINE.to_string());
+
+                    if let Err(e) = client.try_subscribe(topics.commands(), QoS::AtLeastOnce) {
+                        warn!("Failed to subscribe to MQTT commands: {}", e);
+                    }
+                }
+                Ok(Event::Incoming(Packet::Publish(packet))) => {
+                    match topics.parse_command(&packet.topic, &packet.payload) {
+                        Ok(command) => commands.send(command).await?,
+                        Err(e) => warn!("Rejected MQTT command on {}: {}", packet.topic, e),
+                    }
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    // Polling again reconnects
+                    warn!("MQTT connection failed: {}", e);
+                    sleep(Duration::from_secs_f32(MQTT_RECONNECT_DELAY)).await;
+                }
+            },
+        }
+    }
+
+    publish(&client, topics.availability(), Topics::OFFLINE.to_string());
+    client.try_disconnect()?;
+
+    // Let the event loop send what's queued until the broker hangs up
+    while let Ok(Ok(_)) =
+        timeout(Duration::from_secs_f32(MQTT_RECONNECT_DELAY), events.poll()).await
+    {}
+
+    Ok(())
+}
+
+async fn door(mut rx: Receiver<Message>, tx: Sender<Message>) -> Result<()> {
+    let config = if let Message::Setup(config) = rx.recv().await? {
+        info!(
+            "Door thread received setup message with config {:?}",
+            config
+        );
+        config
+    } else {
+        bail!("Door thread did not receive setup message");
+    };
+
+    let Some(door) = config.door_switch() else {
+        info!("No door switch configured, exiting door thread");
+        return Ok(());
+    };
+
+    let gpio = Gpio::new()?;
+    let switch = Switch::new(gpio.get(door.pin)?.into_input_pullup(), door.active);
+    let mut open = false;
+
+    loop {
+        select! {
+            message = rx.recv() => {
+                if let Message::Exit = message? {
+                    info!("Received exit message on door thread, exiting");
+                    break;
+                }
+            }
+            _ = sleep(Duration::from_secs_f32(DOOR_POLL_INTERVAL)) => {
+                if switch.is_active() != open {
+                    open = switch.is_active();
+
+                    if open {
+                        info!("Door thread detected door opened");
+                        tx.send(Message::DoorOpened)?;
+                    } else {
+                        info!("Door thread detected door closed");
+                        tx.send(Message::DoorClosed(Local::now()))?;
+                    }
                 }
             }
         }
@@ -251,6 +1206,90 @@ async fn fan(mut rx: Receiver<Message>) -> Result<()> {
     Ok(())
 }
 
+/// Let the other tasks know when an on/off actuator is switched
+fn report(
+    tx: &Sender<Message>,
+    actuator: Actuator,
+    on: bool,
+    last_on: &mut Option<bool>,
+) -> Result<()> {
+    if *last_on != Some(on) {
+        *last_on = Some(on);
+        tx.send(Message::ActuatorState(actuator, on))?;
+    }
+
+    Ok(())
+}
+
+/// Act on a command, returning whether it asked for a status update
+fn handle_command(
+    command: Command,
+    from: &str,
+    tx: &Sender<Message>,
+    override_ends: &mut Vec<DateTime<Local>>,
+) -> Result<bool> {
+    match command {
+        Command::Override {
+            actuator,
+            on,
+            seconds,
+        } => {
+            let held = match Override::new(on, seconds, &Local::now()) {
+                Ok(held) => held,
+                Err(e) => {
+                    warn!("Rejected override of {} from {}: {}", actuator, from, e);
+                    return Ok(false);
+                }
+            };
+            info!("{} overrode {} with {:?}", from, actuator, held);
+            override_ends.extend(held.until);
+            tx.send(Message::Override(actuator, Some(held)))?;
+            Ok(false)
+        }
+        Command::Auto { actuator } => {
+            info!("{} handed {} back to automatic", from, actuator);
+            tx.send(Message::Override(actuator, None))?;
+            Ok(false)
+        }
+        Command::Status => {
+            info!("{} asked for a status update", from);
+            Ok(true)
+        }
+    }
+}
+
+/// Take all the status messages the tasks have sent since the last call
+fn collect_status(
+    rx: &mut Receiver<Message>,
+    fan_rpm: &mut Vec<f64>,
+    alarms: &mut HashSet<Alarm>,
+    state: &mut State,
+) {
+    loop {
+        match rx.try_recv() {
+            Ok(Message::FanSpeed(rpm)) => *fan_rpm = rpm,
+            Ok(Message::LightLevel(level)) => state.light_level = level,
+            Ok(Message::ActuatorState(Actuator::Mist, on)) => state.mist_on = on,
+            Ok(Message::ActuatorState(Actuator::Fan, on)) => state.fan_on = on,
+            Ok(Message::Dli(dli)) => state.dli = dli,
+            Ok(Message::Pumped(ledger, last_watered)) => {
+                state.pump = ledger;
+                state.last_watered = last_watered;
+            }
+            Ok(Message::Alarm(alarm)) => {
+                warn!("Alarm raised: {}", alarm);
+                alarms.insert(alarm);
+            }
+            Ok(Message::AlarmCleared(alarm)) => {
+                info!("Alarm cleared: {}", alarm);
+                alarms.remove(&alarm);
+            }
+            Ok(_) | Err(TryRecvError::Lagged(_)) => {}
+            Err(_) => break,
+        }
+    }
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
     let args = Args::parse();
@@ -265,6 +1304,11 @@ async fn main() -> Result<()> {
 
     sock.set_broadcast(true)?;
 
+    let command_addr = SocketAddrV4::new(args.listen_addr, args.command_port);
+    let commands = UdpSocket::bind(command_addr).await?;
+
+    info!("Listening for commands on {}", command_addr);
+
     let config = Config::from_file(&args.config_file).await?;
 
     let file_appender = daily("/var/log", "grobot.log");
@@ -277,10 +1321,28 @@ async fn main() -> Result<()> {
 
     set_global_default(subscriber)?;
 
-    let (tx, _rx): (Sender<Message>, Receiver<Message>) = broadcast(16);
+    let today = Local::now().date_naive();
+
+    for actuator in Actuator::ALL {
+        info!(
+            "Scheduled {} windows for {}: {:?}",
+            actuator,
+            today,
+            config.windows(actuator, today)
+        );
+    }
+
+    let (tx, _rx): (Sender<Message>, Receiver<Message>) = broadcast(BUS_CAPACITY);
     let fan_rx = tx.subscribe();
     let light_rx = tx.subscribe();
     let mist_rx = tx.subscribe();
+    let door_rx = tx.subscribe();
+    let pump_rx = tx.subscribe();
+    let camera_rx = tx.subscribe();
+    let http_rx = tx.subscribe();
+    let mqtt_rx = tx.subscribe();
+    let history_rx = tx.subscribe();
+    let mut status_rx = tx.subscribe();
 
     let (stop_tx, mut stop_rx) = oneshot();
 
@@ -290,13 +1352,76 @@ async fn main() -> Result<()> {
         stop_tx.send(Message::Exit).unwrap();
     });
 
-    spawn(light(light_rx));
-    spawn(fan(fan_rx));
-    spawn(mist(mist_rx));
+    spawn(light(light_rx, tx.clone()));
+    spawn(fan(fan_rx, tx.clone()));
+    spawn(mist(mist_rx, tx.clone()));
+    spawn(door(door_rx, tx.clone()));
+    spawn(pump(pump_rx, tx.clone()));
+    spawn(camera(camera_rx));
+
+    let (http_tx, mut http_commands) = mpsc::channel(HTTP_COMMAND_QUEUE);
+    let http_addr = SocketAddr::from(SocketAddrV4::new(args.listen_addr, args.http_port));
+    let source = read_to_string(&args.config_file).await?;
+    let database = match History::open(config.history()) {
+        Ok(database) => {
+            let database = Arc::new(database);
+            spawn(history(history_rx, database.clone()));
+            Some(database)
+        }
+        Err(e) => {
+            warn!(
+                "Could not open history database {:?}, not keeping history: {}",
+                config.history().path,
+                e
+            );
+            None
+        }
+    };
+
+    spawn(http(http_rx, http_tx, http_addr, source, database));
+
+    let (mqtt_tx, mut mqtt_commands) = mpsc::channel(MQTT_COMMAND_QUEUE);
+    spawn(mqtt(mqtt_rx, mqtt_tx));
+
+    let mut co2_sensor = match config.co2_sensor() {
+        Some(sensor) => Some(Co2Sensor::new(sensor)?),
+        None => None,
+    };
+
+    let mut soil = match config.soil() {
+        Some(soil) => Some((Ads1115::new(soil.address)?, Soil::new(soil.clone()))),
+        None => None,
+    };
 
-    tx.send(Message::Setup(config))?;
+    tx.send(Message::Setup(Box::new(config.clone())))?;
 
     let mut environment = Environment::default();
+    let mut fan_rpm = Vec::new();
+    let mut alarms = HashSet::new();
+
+    let mut state = match State::load(&args.state_file).await {
+        Ok(state) => {
+            info!("Restoring state saved at {:?}", state.saved_at);
+            state.without_stale(&Local::now())
+        }
+        Err(e) => {
+            warn!("Could not load state from {:?}: {}", args.state_file, e);
+            State::default()
+        }
+    };
+
+    environment.restore(&state.readings, &state.co2);
+
+    let started = Instant::now();
+    let boot_id = rand::random();
+    let hostname = hostname().await;
+    let mut seq = 0;
+    let mut health = SensorHealth {
+        climate: environment.has_readings(),
+        co2: co2_sensor.as_ref().map(|_| false),
+        soil: soil.as_ref().map(|_| false),
+    };
+    tx.send(Message::Restore(Box::new(state.clone())))?;
 
     info!("Taking initial sensor readings");
 
@@ -310,51 +1435,212 @@ async fn main() -> Result<()> {
         if let Ok(Message::Exit) = stop_rx.try_recv() {
             info!("Got exit message on main thread, exiting");
             tx.send(Message::Exit)?;
-            break;
+            return Ok(());
         }
     }
 
+    // Decide on the actuators now rather than a whole cycle from now, on the schedules alone
+    // if the sensor gave no readings
+    if environment.has_readings() {
+        tx.send(Message::Environment((
+            environment.temp(),
+            environment.humidity(),
+        )))?;
+    } else {
+        warn!("No sensor readings at startup, following the schedules only");
+    }
+
+    tx.send(Message::Time(Local::now()))?;
+
+    let mut sampling = interval(Duration::from_secs_f32(SENSOR_SAMPLE_INTERVAL));
+    sampling.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    let cycle_interval = Duration::from_secs_f32(MAINTHREAD_CYCLE_INTERVAL);
+    let mut cycle = interval_at(Instant::now() + cycle_interval, cycle_interval);
+    cycle.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    let mut auth = config.auth().map(|auth| Authenticator::new(&auth.key));
+
+    if auth.is_none() {
+        warn!("No key configured, updates are sent unsigned and commands are ignored");
+    }
+
+    let mut command_buf = vec![0u8; COMMAND_BUFFER_SIZE];
+    let mut override_ends = Vec::new();
+    let mut reply_to = None;
+
     loop {
-        info!("Taking sensor readings on main thread");
+        // Wake right at the next scheduled change so short windows start and end on time, and
+        // so do overrides
+        let now = Local::now();
+        override_ends.retain(|end| *end > now);
+
+        let transition = config
+            .next_transition(&now)
+            .into_iter()
+            .chain(override_ends.iter().copied())
+            .min()
+            .map(|transition| Instant::now() + (transition - now).to_std().unwrap_or_default());
 
-        for _ in 0..SENSOR_READINGS {
-            if let Ok(reading) = dht22_read(SENSOR_PIN) {
-                environment.add_reading(reading);
+        let report = select! {
+            _ = &mut stop_rx => {
+                info!("Got exit message on main thread, exiting");
+                tx.send(Message::Exit)?;
+                break;
+            }
+            _ = sleep_until(transition.unwrap_or_else(Instant::now)), if transition.is_some() => {
+                info!("Reached a scheduled transition");
+                tx.send(Message::Time(Local::now()))?;
+                false
             }
+            received = commands.recv_from(&mut command_buf) => {
+                let (len, addr) = received?;
 
-            sleep(Duration::from_secs_f32(SENSOR_READING_INTERVAL)).await;
-        }
+                let packet = String::from_utf8_lossy(&command_buf[..len]);
 
-        let msg = environment.json()?;
+                // Only commands signed with the key are trusted to switch the relays
+                let command = match auth.as_mut() {
+                    Some(auth) => auth.open(&packet).and_then(|payload| Command::parse(&payload)),
+                    None => Err(anyhow!("no key is configured to authenticate commands")),
+                };
 
-        info!("Broadcasting sensor readings: '{}'", msg);
+                match command {
+                    Ok(received) => {
+                        let report = handle_command(received, &addr.to_string(), &tx, &mut override_ends)?;
 
-        if let Err(e) = sock.send_to(msg.as_bytes(), broadcast_addr).await {
-            error!("Error sending message: {}", e);
-        }
+                        if report {
+                            reply_to = Some(addr);
+                        }
 
-        tx.send(Message::Environment((
-            environment.temp(),
-            environment.humidity(),
-        )))?;
+                        report
+                    }
+                    Err(e) => {
+                        warn!("Rejected command from {}: {}", addr, e);
+                        false
+                    }
+                }
+            }
+            Some(received) = http_commands.recv() => {
+                handle_command(received, "HTTP API", &tx, &mut override_ends)?
+            }
+            Some(received) = mqtt_commands.recv() => {
+                handle_command(received, "MQTT", &tx, &mut override_ends)?
+            }
+            _ = sampling.tick() => {
+                info!("Taking sensor readings on main thread");
 
-        if let Ok(Message::Exit) = stop_rx.try_recv() {
-            info!("Got exit message on main thread, exiting");
-            tx.send(Message::Exit)?;
-            break;
-        }
+                health.climate = environment.read(SENSOR_PIN).await;
 
-        info!("Sleeping for cycle interval");
+                match environment.latest() {
+                    Some(reading) if health.climate => tx.send(Message::Reading(reading))?,
+                    _ => tx.send(Message::ReadFailed("climate"))?,
+                };
 
-        if let Ok(Message::Exit) = stop_rx.try_recv() {
-            info!("Got exit message on main thread, exiting");
-            tx.send(Message::Exit)?;
-            break;
-        }
+                if let Some((adc, soil)) = soil.as_mut() {
+                    health.soil = Some(soil.read(adc));
+
+                    if health.soil == Some(false) {
+                        tx.send(Message::ReadFailed("soil"))?;
+                    }
+                }
+
+                if let Some(sensor) = co2_sensor.as_mut() {
+                    match sensor.read() {
+                        Ok(co2) => {
+                            environment.add_co2(co2);
+                            health.co2 = Some(true);
+                        }
+                        Err(e) => {
+                            warn!("Failed to read from CO2 sensor: {}", e);
+                            health.co2 = Some(false);
+                            tx.send(Message::ReadFailed("co2"))?;
+                        }
+                    }
+                }
 
-        sleep(Duration::from_secs_f32(MAINTHREAD_CYCLE_INTERVAL)).await;
+                false
+            }
+            _ = cycle.tick() => true,
+        };
+
+        if report {
+            let moisture = soil
+                .as_ref()
+                .map(|(_, soil)| soil.moisture())
+                .unwrap_or_default();
+
+            collect_status(&mut status_rx, &mut fan_rpm, &mut alarms, &mut state);
+
+            state.saved_at = Some(Local::now());
+            state.readings = environment.readings();
+            state.co2 = environment.co2_readings();
+
+            if let Err(e) = state.save(&args.state_file).await {
+                error!("Error saving state to {:?}: {}", args.state_file, e);
+            }
+
+            seq += 1;
+
+            let update = NetworkUpdate {
+                version: NetworkUpdate::VERSION,
+                seq,
+                hostname: hostname.clone(),
+                boot_id,
+                uptime: started.elapsed().as_secs(),
+                temp: environment.has_readings().then(|| environment.temp()),
+                humidity: environment.has_readings().then(|| environment.humidity()),
+                co2: environment.co2(),
+                soil: moisture.clone(),
+                light_level: state.light_level,
+                mist_on: state.mist_on,
+                fan_duty: if state.fan_on {
+                    config.fan_power().as_duty_cycle()
+                } else {
+                    0.0
+                },
+                fan_rpm: fan_rpm.clone(),
+                thresholds: config.thresholds().clone(),
+                sensors: health.clone(),
+                alarms: alarms.iter().cloned().collect(),
+            };
+
+            let msg = to_string(&update)?;
+            tx.send(Message::Status(Box::new(update)))?;
+
+            info!("Broadcasting sensor readings: '{}'", msg);
+
+            let msg = match auth.as_ref() {
+                Some(auth) => auth.seal(&msg)?,
+                None => msg,
+            };
 
-        tx.send(Message::Time(Local::now()))?;
+            if let Err(e) = sock.send_to(msg.as_bytes(), broadcast_addr).await {
+                error!("Error sending message: {}", e);
+            }
+
+            if let Some(addr) = reply_to.take() {
+                if let Err(e) = commands.send_to(msg.as_bytes(), addr).await {
+                    error!("Error replying to {}: {}", addr, e);
+                }
+            }
+
+            if environment.has_readings() {
+                tx.send(Message::Environment((
+                    environment.temp(),
+                    environment.humidity(),
+                )))?;
+            }
+
+            if soil.is_some() {
+                tx.send(Message::Soil(moisture))?;
+            }
+
+            if let Some(co2) = environment.co2() {
+                tx.send(Message::Co2(co2))?;
+            }
+
+            tx.send(Message::Time(Local::now()))?;
+        }
     }
 
     info!("grobot done, goodbye");
@@ -0,0 +1,161 @@
+use anyhow::{anyhow, Context, Result};
+use chrono::{DateTime, Duration, Local, NaiveDate, NaiveDateTime, TimeZone};
+use clap::{Parser, Subcommand, ValueEnum};
+use grobot::{parse_duration, Actuator, Config, Export, History};
+use std::{
+    fs::File,
+    io::{stdout, BufWriter, Write},
+    path::PathBuf,
+};
+
+#[derive(Parser)]
+struct Args {
+    #[clap(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Check a configuration file and print the windows each actuator is scheduled to be on
+    Check {
+        /// Path to a configuration file in TOML format
+        config_file: PathBuf,
+        #[clap(short, long)]
+        /// Date to print the schedule for in %Y-%m-%d format, today if not given
+        date: Option<NaiveDate>,
+    },
+    /// Export the history a controller stored over a time range
+    Export {
+        /// Path to the controller's configuration file, for where its history is
+        config_file: PathBuf,
+        #[clap(long, value_parser = parse_time)]
+        /// Start of the range like `2023-04-23` or `2023-04-23 06:00`, a day before its end if
+        /// not given
+        from: Option<DateTime<Local>>,
+        #[clap(long, value_parser = parse_time)]
+        /// End of the range, now if not given
+        to: Option<DateTime<Local>>,
+        #[clap(short, long, value_parser = parse_duration)]
+        /// Average over windows this long like `1h`, or a row for each reading if not given
+        interval: Option<Duration>,
+        #[clap(short, long, value_enum, default_value_t = Format::Csv)]
+        format: Format,
+        #[clap(short, long)]
+        /// File to write to, or standard output if not given
+        output: Option<PathBuf>,
+        #[clap(long)]
+        /// History database to read, the one in the configuration if not given
+        db: Option<PathBuf>,
+    },
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Format {
+    Csv,
+    /// A JSON object on each line
+    Json,
+}
+
+/// Parse a local time like `2023-04-23`, `2023-04-23 06:00` or an RFC 3339 time
+fn parse_time(s: &str) -> Result<DateTime<Local>> {
+    if let Ok(time) = DateTime::parse_from_rfc3339(s) {
+        return Ok(time.with_timezone(&Local));
+    }
+
+    let time = ["%Y-%m-%d %H:%M", "%Y-%m-%dT%H:%M", "%Y-%m-%d %H:%M:%S"]
+        .iter()
+        .find_map(|format| NaiveDateTime::parse_from_str(s, format).ok())
+        .or_else(|| {
+            NaiveDate::parse_from_str(s, "%Y-%m-%d")
+                .ok()
+                .and_then(|date| date.and_hms_opt(0, 0, 0))
+        })
+        .ok_or_else(|| anyhow!("Expected a time like 2023-04-23 or 2023-04-23 06:00"))?;
+
+    Local
+        .from_local_datetime(&time)
+        .earliest()
+        .ok_or_else(|| anyhow!("{} doesn't exist in the local time zone", s))
+}
+
+async fn check(config_file: PathBuf, date: Option<NaiveDate>) -> Result<()> {
+    let config = Config::from_file(&config_file).await?;
+    let date = date.unwrap_or_else(|| Local::now().date_naive());
+
+    println!("{} is valid, schedule for {}:", config_file.display(), date);
+
+    for actuator in Actuator::ALL {
+        println!();
+        println!("{}:", actuator);
+
+        for (on, off) in config.windows(actuator, date) {
+            println!("    {} - {}", on.format("%H:%M:%S"), off.format("%H:%M:%S"));
+        }
+    }
+
+    Ok(())
+}
+
+struct ExportArgs {
+    config_file: PathBuf,
+    from: Option<DateTime<Local>>,
+    to: Option<DateTime<Local>>,
+    interval: Option<Duration>,
+    format: Format,
+    output: Option<PathBuf>,
+    db: Option<PathBuf>,
+}
+
+async fn export(args: ExportArgs) -> Result<()> {
+    let config = Config::from_file(&args.config_file).await?;
+    let db = args.db.unwrap_or_else(|| config.history().path.clone());
+    let history = History::open_read_only(config.history(), &db)
+        .with_context(|| format!("Could not open history database {:?}", db))?;
+
+    let to = args.to.unwrap_or_else(Local::now);
+    let from = args.from.unwrap_or(to - Duration::days(1));
+    let export = Export::new(&history, &from, &to, args.interval)?;
+
+    let mut out: Box<dyn Write> = match &args.output {
+        Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+        None => Box::new(BufWriter::new(stdout().lock())),
+    };
+
+    match args.format {
+        Format::Csv => export.write_csv(&mut out)?,
+        Format::Json => export.write_json_lines(&mut out)?,
+    }
+
+    out.flush()?;
+
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args = Args::parse();
+
+    match args.command {
+        Command::Check { config_file, date } => check(config_file, date).await,
+        Command::Export {
+            config_file,
+            from,
+            to,
+            interval,
+            format,
+            output,
+            db,
+        } => {
+            export(ExportArgs {
+                config_file,
+                from,
+                to,
+                interval,
+                format,
+                output,
+                db,
+            })
+            .await
+        }
+    }
+}
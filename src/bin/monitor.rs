@@ -1,12 +1,21 @@
-use anyhow::Result;
-use clap::Parser;
-use grobot::{NetworkUpdate, PORT};
-use serde_json::from_str;
+use anyhow::{ensure, Context, Result};
+use chrono::{Duration, Local};
+use clap::{Parser, Subcommand, ValueEnum};
+use grobot::{
+    parse_duration, Actuator, Authenticator, Command, NetworkUpdate, Sample, UpdateTracker,
+    COMMAND_PORT, HTTP_PORT, PORT,
+};
+use hyper::{body::to_bytes, Client, Uri};
+use serde_json::{from_slice, to_string, to_string_pretty};
 use std::net::{Ipv4Addr, SocketAddrV4};
-use tokio::net::UdpSocket;
-use tracing::{info, Level};
+use tokio::{net::UdpSocket, time::timeout};
+use tracing::{info, warn, Level};
 
 const BIND_ADDR: Ipv4Addr = Ipv4Addr::new(0, 0, 0, 0);
+// Largest update we expect, with room for plenty of soil sensors and alarms
+const UPDATE_BUFFER_SIZE: usize = 4096;
+// Number of seconds to wait for a controller to answer a status request
+const STATUS_TIMEOUT: u64 = 5;
 
 #[derive(Parser)]
 struct Args {
@@ -19,21 +28,214 @@ struct Args {
     #[clap(short = 'L', long, default_value_t = BIND_ADDR)]
     // Listen address
     listen_addr: Ipv4Addr,
+    #[clap(short = 'C', long, default_value_t = Ipv4Addr::BROADCAST)]
+    /// Controller to send commands to, or all of them on the network by default
+    controller: Ipv4Addr,
+    #[clap(short, long, default_value_t = COMMAND_PORT)]
+    /// Port the controller listens for commands on
+    command_port: u16,
+    #[clap(short = 'H', long, default_value_t = HTTP_PORT)]
+    /// Port the controller serves its HTTP API on
+    http_port: u16,
+    #[clap(short, long)]
+    /// Key shared with the controller, to check updates with and sign commands with
+    key: Option<String>,
+    #[clap(subcommand)]
+    request: Option<Request>,
+}
+
+#[derive(Subcommand)]
+enum Request {
+    /// Hold an actuator on or off
+    Override {
+        /// light, mist or fan
+        actuator: Actuator,
+        #[clap(value_enum)]
+        state: Power,
+        /// How long to hold it for, like `30m`, or until it is handed back if not given
+        #[clap(long = "for", value_parser = parse_duration)]
+        duration: Option<Duration>,
+    },
+    /// Hand an actuator back to its schedule and thresholds
+    Auto {
+        /// light, mist or fan
+        actuator: Actuator,
+    },
+    /// Ask for a status update and print it
+    Status,
+    /// Print readings of a metric from the controller's history, or list the metrics if none
+    /// is given
+    History {
+        /// Metric like `temperature` or `soil/basil`
+        metric: Option<String>,
+        /// How far back to go
+        #[clap(long, default_value = "24h", value_parser = parse_duration)]
+        since: Duration,
+    },
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Power {
+    On,
+    Off,
+}
+
+/// Send a command to the controller, printing the status it sends back if asked for one
+async fn send(args: &Args, command: Command) -> Result<()> {
+    let mut auth = args
+        .key
+        .as_deref()
+        .map(Authenticator::new)
+        .context("A --key is needed to send commands")?;
+
+    let sock = UdpSocket::bind(SocketAddrV4::new(args.listen_addr, 0)).await?;
+    sock.set_broadcast(true)?;
+    sock.send_to(
+        auth.seal(&to_string(&command)?)?.as_bytes(),
+        SocketAddrV4::new(args.controller, args.command_port),
+    )
+    .await?;
+
+    if let Command::Status = command {
+        let mut buf = vec![0u8; UPDATE_BUFFER_SIZE];
+        let (len, _) = timeout(
+            std::time::Duration::from_secs(STATUS_TIMEOUT),
+            sock.recv_from(&mut buf),
+        )
+        .await
+        .context("No status from the controller")??;
+
+        let payload = auth.open(&String::from_utf8_lossy(&buf[..len]))?;
+        let update = NetworkUpdate::parse(&payload)?;
+        println!("{}", to_string_pretty(&update)?);
+    }
+
+    Ok(())
+}
+
+/// Print readings of a metric since a while ago, or the metrics there are readings of, as the
+/// controller's HTTP API serves them
+async fn history(args: &Args, metric: Option<&str>, since: Duration) -> Result<()> {
+    ensure!(
+        !args.controller.is_broadcast(),
+        "A --controller address is needed to read its history"
+    );
+
+    let mut uri = format!("http://{}:{}/api/readings", args.controller, args.http_port);
+    if let Some(metric) = metric {
+        uri.push('?');
+        uri.push_str(&serde_urlencoded::to_string([
+            ("metric", metric.to_string()),
+            ("from", (Local::now() - since).to_rfc3339()),
+        ])?);
+    }
+    let uri: Uri = uri.parse()?;
+
+    let response = timeout(
+        std::time::Duration::from_secs(STATUS_TIMEOUT),
+        Client::new().get(uri),
+    )
+    .await
+    .context("No answer from the controller")??;
+    let status = response.status();
+    let body = to_bytes(response.into_body()).await?;
+
+    ensure!(
+        status.is_success(),
+        "Controller answered {}: {}",
+        status,
+        String::from_utf8_lossy(&body)
+    );
+
+    match metric {
+        Some(_) => {
+            for sample in from_slice::<Vec<Sample>>(&body)? {
+                println!("{}\t{}", sample.time.to_rfc3339(), sample.value);
+            }
+        }
+        None => {
+            for metric in from_slice::<Vec<String>>(&body)? {
+                println!("{}", metric);
+            }
+        }
+    }
+
+    Ok(())
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
     let args = Args::parse();
+
+    if let Some(request) = &args.request {
+        let command = match request {
+            Request::Override {
+                actuator,
+                state,
+                duration,
+            } => Command::Override {
+                actuator: *actuator,
+                on: matches!(state, Power::On),
+                seconds: duration.map(|duration| duration.num_seconds() as u64),
+            },
+            Request::Auto { actuator } => Command::Auto {
+                actuator: *actuator,
+            },
+            Request::Status => Command::Status,
+            Request::History { metric, since } => {
+                return history(&args, metric.as_deref(), *since).await;
+            }
+        };
+
+        return send(&args, command).await;
+    }
+
     let bind_addr = SocketAddrV4::new(args.listen_addr, args.port);
     let sock = UdpSocket::bind(bind_addr).await?;
     sock.set_broadcast(true)?;
 
+    let mut tracker = UpdateTracker::default();
+    let mut auth = args.key.as_deref().map(Authenticator::new);
+
     loop {
         // Receive on the socket
-        let mut buf = vec![0u8; 1024];
+        let mut buf = vec![0u8; UPDATE_BUFFER_SIZE];
         let (len, addr) = sock.recv_from(&mut buf).await?;
         info!("Received {} bytes from {}", len, addr);
-        let update: NetworkUpdate = from_str(&String::from_utf8_lossy(&buf[..len]))?;
+
+        let packet = String::from_utf8_lossy(&buf[..len]).to_string();
+
+        let payload = match auth.as_mut() {
+            Some(auth) => match auth.open(&packet) {
+                Ok(payload) => payload,
+                Err(e) => {
+                    warn!("Rejected update from {}: {}", addr, e);
+                    continue;
+                }
+            },
+            None => packet,
+        };
+
+        let update = match NetworkUpdate::parse(&payload) {
+            Ok(update) => update,
+            Err(e) => {
+                warn!("Rejected update from {}: {}", addr, e);
+                continue;
+            }
+        };
+
+        if !tracker.accept(&update) {
+            info!(
+                "Dropped duplicate update {} from {}",
+                update.seq, update.hostname
+            );
+            continue;
+        }
+
         info!("Received update {:?}", update);
+
+        for alarm in update.alarms() {
+            warn!("Alarm from {}: {}", addr, alarm);
+        }
     }
 }
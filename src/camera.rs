@@ -0,0 +1,80 @@
+use anyhow::{ensure, Result};
+use chrono::{DateTime, Duration, Local};
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use tokio::process::Command;
+
+use crate::deserialize_duration;
+
+fn default_camera_device() -> PathBuf {
+    PathBuf::from(CameraConfig::DEFAULT_DEVICE)
+}
+
+/// A V4L2 camera that takes time-lapse stills while the light is on
+#[derive(Deserialize, Debug, Clone)]
+pub struct CameraConfig {
+    /// Video device of the camera
+    #[serde(default = "default_camera_device")]
+    pub device: PathBuf,
+    /// Directory the stills are saved in, under a directory for each day
+    pub directory: PathBuf,
+    /// Time between stills
+    #[serde(deserialize_with = "deserialize_duration")]
+    pub interval: Duration,
+    /// Resolution to capture at like `1920x1080`, or the camera's default if not given
+    pub resolution: Option<String>,
+}
+
+impl CameraConfig {
+    const DEFAULT_DEVICE: &'static str = "/dev/video0";
+
+    pub fn validate(&self) -> Result<()> {
+        ensure!(
+            self.interval > Duration::zero(),
+            "Camera interval must be longer than zero"
+        );
+
+        Ok(())
+    }
+
+    /// Where the still taken at a time is saved, its sidecar has the same path with a `json`
+    /// extension
+    pub fn image_path(&self, time: &DateTime<Local>) -> PathBuf {
+        self.directory
+            .join(time.format("%Y-%m-%d").to_string())
+            .join(time.format("%H%M%S.jpg").to_string())
+    }
+
+    /// Take a still and save it to a path with ffmpeg
+    pub async fn capture(&self, path: &PathBuf) -> Result<()> {
+        let mut command = Command::new("ffmpeg");
+        command.args(["-loglevel", "error", "-y", "-f", "v4l2"]);
+
+        if let Some(resolution) = &self.resolution {
+            command.args(["-video_size", resolution]);
+        }
+
+        let status = command
+            .arg("-i")
+            .arg(&self.device)
+            .args(["-frames:v", "1"])
+            .arg(path)
+            .status()
+            .await?;
+
+        ensure!(status.success(), "ffmpeg exited with {}", status);
+
+        Ok(())
+    }
+}
+
+/// The conditions in the cabinet when a still was taken, saved next to it
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Snapshot {
+    pub time: DateTime<Local>,
+    pub temp: Option<f32>,
+    pub humidity: Option<f32>,
+    pub co2: Option<f32>,
+    /// Light level in percent
+    pub light_level: f64,
+}
@@ -0,0 +1,125 @@
+use anyhow::{bail, ensure, Result};
+use rppal::{
+    i2c::I2c,
+    uart::{Parity, Queue, Uart},
+};
+use serde::Deserialize;
+use std::{path::PathBuf, thread::sleep, time::Duration};
+
+fn default_serial_device() -> PathBuf {
+    PathBuf::from(Co2Sensor::DEFAULT_SERIAL_DEVICE)
+}
+
+fn default_scd4x_address() -> u16 {
+    Co2Sensor::SCD4X_ADDRESS
+}
+
+/// Which CO2 sensor is connected and where
+#[derive(Deserialize, Debug, Clone)]
+#[serde(tag = "kind")]
+pub enum Co2SensorConfig {
+    /// Winsen MH-Z19 on a serial port
+    MhZ19 {
+        #[serde(default = "default_serial_device")]
+        device: PathBuf,
+    },
+    /// Sensirion SCD40/SCD41 on the I2C bus
+    Scd4x {
+        #[serde(default = "default_scd4x_address")]
+        address: u16,
+    },
+}
+
+pub enum Co2Sensor {
+    MhZ19(Uart),
+    Scd4x(I2c),
+}
+
+impl Co2Sensor {
+    const DEFAULT_SERIAL_DEVICE: &'static str = "/dev/serial0";
+    const MHZ19_BAUD_RATE: u32 = 9600;
+    const MHZ19_READ: [u8; 9] = [0xff, 0x01, 0x86, 0x00, 0x00, 0x00, 0x00, 0x00, 0x79];
+    const MHZ19_TIMEOUT: Duration = Duration::from_secs(1);
+    const SCD4X_ADDRESS: u16 = 0x62;
+    const SCD4X_START_PERIODIC_MEASUREMENT: [u8; 2] = [0x21, 0xb1];
+    const SCD4X_READ_MEASUREMENT: [u8; 2] = [0xec, 0x05];
+    /// Time the SCD4x needs between a command and reading the response
+    const SCD4X_COMMAND_TIME: Duration = Duration::from_millis(1);
+
+    pub fn new(config: &Co2SensorConfig) -> Result<Self> {
+        match config {
+            Co2SensorConfig::MhZ19 { device } => {
+                let mut uart = Uart::with_path(device, Self::MHZ19_BAUD_RATE, Parity::None, 8, 1)?;
+                uart.set_read_mode(Self::MHZ19_READ.len() as u8, Self::MHZ19_TIMEOUT)?;
+                Ok(Self::MhZ19(uart))
+            }
+            Co2SensorConfig::Scd4x { address } => {
+                let mut i2c = I2c::new()?;
+                i2c.set_slave_address(*address)?;
+                i2c.write(&Self::SCD4X_START_PERIODIC_MEASUREMENT)?;
+                Ok(Self::Scd4x(i2c))
+            }
+        }
+    }
+
+    /// Read the CO2 concentration in ppm
+    pub fn read(&mut self) -> Result<f32> {
+        match self {
+            Self::MhZ19(uart) => {
+                uart.flush(Queue::Input)?;
+                uart.write(&Self::MHZ19_READ)?;
+
+                let mut buf = [0u8; 9];
+                let len = uart.read(&mut buf)?;
+
+                ensure!(len == buf.len(), "Short read of {} bytes from MH-Z19", len);
+                ensure!(
+                    buf[0] == 0xff && buf[1] == 0x86,
+                    "Unexpected response from MH-Z19"
+                );
+                ensure!(mhz19_checksum(&buf) == buf[8], "Bad checksum from MH-Z19");
+
+                Ok(u16::from_be_bytes([buf[2], buf[3]]) as f32)
+            }
+            Self::Scd4x(i2c) => {
+                i2c.write(&Self::SCD4X_READ_MEASUREMENT)?;
+                sleep(Self::SCD4X_COMMAND_TIME);
+
+                // CO2, temperature and humidity, each as a word followed by a CRC
+                let mut buf = [0u8; 9];
+                i2c.read(&mut buf)?;
+
+                if sensirion_crc(&buf[0..2]) != buf[2] {
+                    bail!("Bad CRC from SCD4x");
+                }
+
+                Ok(u16::from_be_bytes([buf[0], buf[1]]) as f32)
+            }
+        }
+    }
+}
+
+/// Checksum of an MH-Z19 frame, over every byte but the start byte and the checksum
+pub fn mhz19_checksum(frame: &[u8; 9]) -> u8 {
+    let sum = frame[1..8].iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+    (!sum).wrapping_add(1)
+}
+
+/// CRC-8 used by Sensirion sensors for each word they send
+pub fn sensirion_crc(data: &[u8]) -> u8 {
+    let mut crc = 0xffu8;
+
+    for byte in data {
+        crc ^= byte;
+
+        for _ in 0..8 {
+            crc = if crc & 0x80 != 0 {
+                (crc << 1) ^ 0x31
+            } else {
+                crc << 1
+            };
+        }
+    }
+
+    crc
+}
@@ -0,0 +1,56 @@
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Duration, Local};
+use serde::{Deserialize, Serialize};
+
+use crate::Actuator;
+
+/// A request sent to the controller's command port
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "command")]
+pub enum Command {
+    /// Turn an actuator on or off, for a number of seconds or until it is handed back
+    Override {
+        actuator: Actuator,
+        on: bool,
+        seconds: Option<u64>,
+    },
+    /// Hand an actuator back to its schedule and thresholds
+    Auto { actuator: Actuator },
+    /// Send a status update back right away
+    Status,
+}
+
+impl Command {
+    pub fn parse(s: &str) -> Result<Self> {
+        Ok(serde_json::from_str(s)?)
+    }
+}
+
+/// An actuator held on or off by a remote command
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Override {
+    pub on: bool,
+    /// When the actuator goes back to automatic, or never if not given
+    pub until: Option<DateTime<Local>>,
+}
+
+impl Override {
+    /// Hold an actuator from a time, failing if it would be held past the end of time
+    pub fn new(on: bool, seconds: Option<u64>, now: &DateTime<Local>) -> Result<Self> {
+        let until = seconds
+            .map(|seconds| {
+                Duration::from_std(std::time::Duration::from_secs(seconds))
+                    .ok()
+                    .and_then(|held| now.checked_add_signed(held))
+                    .ok_or_else(|| anyhow!("Can't hold an actuator for {} seconds", seconds))
+            })
+            .transpose()?;
+
+        Ok(Self { on, until })
+    }
+
+    /// Whether the override still holds at a time
+    pub fn active(&self, time: &DateTime<Local>) -> bool {
+        self.until.is_none_or(|until| *time < until)
+    }
+}
@@ -0,0 +1,105 @@
+use anyhow::Result;
+use chrono::{DateTime, Local, NaiveDate};
+use rppal::i2c::I2c;
+use serde::{Deserialize, Serialize};
+
+/// Micromoles in a mole, PPFD is in umol/m^2/s and DLI is in mol/m^2/day
+const MICROMOLES: f64 = 1_000_000.0;
+
+fn default_light_sensor_address() -> u16 {
+    LightSensor::DEFAULT_ADDRESS
+}
+
+fn default_lux_to_ppfd() -> f64 {
+    LightSensor::DEFAULT_LUX_TO_PPFD
+}
+
+/// A BH1750 ambient light sensor on the I2C bus
+#[derive(Deserialize, Debug, Clone)]
+pub struct LightSensorConfig {
+    /// I2C address of the sensor, 0x23 unless the ADDR pin is pulled high
+    #[serde(default = "default_light_sensor_address")]
+    pub address: u16,
+    /// Conversion factor from lux to PPFD, which depends on the spectrum of the light
+    #[serde(default = "default_lux_to_ppfd")]
+    pub lux_to_ppfd: f64,
+}
+
+pub struct LightSensor {
+    i2c: I2c,
+    lux_to_ppfd: f64,
+}
+
+impl LightSensor {
+    const DEFAULT_ADDRESS: u16 = 0x23;
+    /// Roughly right for sunlight, white LEDs are usually between 0.014 and 0.02
+    const DEFAULT_LUX_TO_PPFD: f64 = 0.0185;
+    /// Continuously measure at 1 lux resolution
+    const CONTINUOUS_HIGH_RES_MODE: u8 = 0x10;
+    /// Counts per lux in high resolution mode
+    const COUNTS_PER_LUX: f64 = 1.2;
+
+    pub fn new(config: &LightSensorConfig) -> Result<Self> {
+        let mut i2c = I2c::new()?;
+        i2c.set_slave_address(config.address)?;
+        i2c.write(&[Self::CONTINUOUS_HIGH_RES_MODE])?;
+
+        Ok(Self {
+            i2c,
+            lux_to_ppfd: config.lux_to_ppfd,
+        })
+    }
+
+    /// Read the illuminance in lux
+    pub fn lux(&mut self) -> Result<f64> {
+        let mut buf = [0u8; 2];
+        self.i2c.read(&mut buf)?;
+        Ok(u16::from_be_bytes(buf) as f64 / Self::COUNTS_PER_LUX)
+    }
+
+    /// Read the PPFD in umol/m^2/s
+    pub fn ppfd(&mut self) -> Result<f64> {
+        Ok(self.lux()? * self.lux_to_ppfd)
+    }
+}
+
+/// Integrates PPFD over the day into a daily light integral
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct DliTracker {
+    date: Option<NaiveDate>,
+    last: Option<(DateTime<Local>, f64)>,
+    dli: f64,
+}
+
+impl DliTracker {
+    /// Record the PPFD at a time. The previous PPFD is assumed to have held since the last
+    /// update. The integral starts over at midnight.
+    pub fn update(&mut self, time: DateTime<Local>, ppfd: f64) {
+        if self.date != Some(time.date_naive()) {
+            self.date = Some(time.date_naive());
+            self.dli = 0.0;
+            self.last = None;
+        }
+
+        if let Some((last_time, last_ppfd)) = self.last {
+            let seconds = (time - last_time).num_milliseconds() as f64 / 1000.0;
+
+            if seconds > 0.0 {
+                self.dli += last_ppfd * seconds / MICROMOLES;
+            }
+        }
+
+        self.last = Some((time, ppfd));
+    }
+
+    /// Forget the last PPFD, so the time the controller was down isn't counted as light when
+    /// picking up after a restart
+    pub fn resume(&mut self) {
+        self.last = None;
+    }
+
+    /// The daily light integral so far today in mol/m^2/day
+    pub fn dli(&self) -> f64 {
+        self.dli
+    }
+}
@@ -0,0 +1,204 @@
+use anyhow::Result;
+use chrono::{DateTime, Duration, Local};
+use serde_json::{Map, Value};
+use std::{collections::BTreeMap, io::Write};
+
+use crate::{Actuator, History, Sample, ThresholdConfig, Transition};
+
+/// Fraction of a window an actuator was on for, going from its state at the start and the
+/// transitions during, or its state at the start if the window has no length. `None` if
+/// nothing is known about it by the end.
+fn on_fraction(
+    mut state: Option<bool>,
+    transitions: &[&Transition],
+    start: DateTime<Local>,
+    end: DateTime<Local>,
+) -> Option<f64> {
+    let mut on = Duration::zero();
+    let mut since = start;
+
+    for transition in transitions {
+        if transition.time <= start {
+            state = Some(transition.on);
+            continue;
+        }
+        if transition.time >= end {
+            break;
+        }
+
+        if state == Some(true) {
+            on = on + (transition.time - since);
+        }
+        since = transition.time;
+        state = Some(transition.on);
+    }
+
+    if end <= start {
+        return state.map(|on| if on { 1.0 } else { 0.0 });
+    }
+
+    if state == Some(true) {
+        on = on + (end - since);
+    }
+
+    state.map(|_| on.num_milliseconds() as f64 / (end - start).num_milliseconds() as f64)
+}
+
+/// The last of a metric's samples at or before a time, going from its value before them
+fn value_at(mut value: Option<f64>, samples: &[Sample], time: DateTime<Local>) -> Option<f64> {
+    for sample in samples {
+        if sample.time > time {
+            break;
+        }
+        value = Some(sample.value);
+    }
+
+    value
+}
+
+/// Stored history over a time range as rows of readings, actuator states and the thresholds
+/// in effect
+#[derive(Debug, Clone)]
+pub struct Export {
+    columns: Vec<String>,
+    rows: Vec<Map<String, Value>>,
+}
+
+impl Export {
+    /// Export what was stored from a time up to another. Readings are averaged over windows of
+    /// an interval and the actuator columns give the fraction of each window they were on, or
+    /// without an interval there is a row for each time readings were taken at and the
+    /// actuator columns give whether they were on. The threshold columns give the thresholds
+    /// in effect at the end of each window, or when the readings were taken.
+    pub fn new(
+        history: &History,
+        from: &DateTime<Local>,
+        to: &DateTime<Local>,
+        interval: Option<Duration>,
+    ) -> Result<Self> {
+        let (thresholds, metrics): (Vec<_>, Vec<_>) = history
+            .metrics()?
+            .into_iter()
+            .partition(|metric| metric.starts_with(ThresholdConfig::METRIC_PREFIX));
+        let mut rows: BTreeMap<DateTime<Local>, Map<String, Value>> = BTreeMap::new();
+
+        for metric in &metrics {
+            let samples = history.aggregate(
+                metric,
+                from,
+                to,
+                interval.unwrap_or_else(|| Duration::seconds(1)),
+            )?;
+
+            for sample in samples {
+                rows.entry(sample.time)
+                    .or_default()
+                    .insert(metric.clone(), sample.value.into());
+            }
+        }
+
+        let transitions = history.transitions(from, to)?;
+
+        let mut columns = vec!["time".to_string()];
+        columns.extend(metrics);
+        columns.extend(
+            Actuator::ALL
+                .iter()
+                .map(|actuator| format!("{}_on", actuator)),
+        );
+        columns.extend(
+            thresholds
+                .iter()
+                .map(|metric| metric[ThresholdConfig::METRIC_PREFIX.len()..].to_string()),
+        );
+
+        for actuator in Actuator::ALL {
+            let initial = history.state_at(actuator, from)?;
+            let transitions: Vec<_> = transitions
+                .iter()
+                .filter(|transition| transition.actuator == actuator)
+                .collect();
+
+            for (time, row) in rows.iter_mut() {
+                // Windows start on a multiple of the interval, so the first can start early
+                let start = (*time).max(*from);
+                let end = match interval {
+                    Some(interval) => (*time + interval).min(*to),
+                    None => start,
+                };
+
+                row.insert(
+                    format!("{}_on", actuator),
+                    on_fraction(initial, &transitions, start, end).into(),
+                );
+            }
+        }
+
+        for metric in &thresholds {
+            let initial = history.value_at(metric, from)?;
+            let samples = history.samples(metric, from, to)?;
+            let column = &metric[ThresholdConfig::METRIC_PREFIX.len()..];
+
+            for (time, row) in rows.iter_mut() {
+                let at = match interval {
+                    Some(interval) => (*time + interval).min(*to) - Duration::seconds(1),
+                    None => *time,
+                };
+
+                row.insert(column.to_string(), value_at(initial, &samples, at).into());
+            }
+        }
+
+        let rows = rows
+            .into_iter()
+            .map(|(time, mut row)| {
+                row.insert("time".to_string(), time.to_rfc3339().into());
+                row
+            })
+            .collect();
+
+        Ok(Self { columns, rows })
+    }
+
+    pub fn columns(&self) -> &[String] {
+        &self.columns
+    }
+
+    pub fn rows(&self) -> &[Map<String, Value>] {
+        &self.rows
+    }
+
+    /// Write the rows as CSV with a header, leaving missing values empty
+    pub fn write_csv(&self, out: &mut impl Write) -> Result<()> {
+        writeln!(out, "{}", self.columns.join(","))?;
+
+        for row in &self.rows {
+            let fields: Vec<String> = self
+                .columns
+                .iter()
+                .map(|column| match row.get(column) {
+                    None | Some(Value::Null) => String::new(),
+                    Some(Value::String(s)) if s.contains([',', '"', '\n']) => {
+                        format!("\"{}\"", s.replace('"', "\"\""))
+                    }
+                    Some(Value::String(s)) => s.clone(),
+                    Some(value) => value.to_string(),
+                })
+                .collect();
+
+            writeln!(out, "{}", fields.join(","))?;
+        }
+
+        Ok(())
+    }
+
+    /// Write the rows as a JSON object on each line
+    pub fn write_json_lines(&self, out: &mut impl Write) -> Result<()> {
+        for row in &self.rows {
+            serde_json::to_writer(&mut *out, row)?;
+            writeln!(out)?;
+        }
+
+        Ok(())
+    }
+}
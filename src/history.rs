@@ -0,0 +1,435 @@
+use anyhow::{ensure, Result};
+use chrono::{DateTime, Duration, Local, TimeZone};
+use rusqlite::{params, Connection, OpenFlags};
+use serde::{Deserialize, Serialize};
+use std::{
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use crate::{deserialize_duration, Actuator, Alarm, ThresholdConfig};
+
+fn default_history_path() -> PathBuf {
+    PathBuf::from(HistoryConfig::DEFAULT_PATH)
+}
+
+fn default_raw_retention() -> Duration {
+    Duration::days(7)
+}
+
+fn default_average_interval() -> Duration {
+    Duration::minutes(5)
+}
+
+fn default_average_retention() -> Duration {
+    Duration::days(365)
+}
+
+/// Where readings, actuator transitions and alarms are kept and for how long
+#[derive(Deserialize, Debug, Clone)]
+pub struct HistoryConfig {
+    /// SQLite database file
+    #[serde(default = "default_history_path")]
+    pub path: PathBuf,
+    /// How long every reading is kept before being averaged
+    #[serde(
+        default = "default_raw_retention",
+        deserialize_with = "deserialize_duration"
+    )]
+    pub raw_retention: Duration,
+    /// Length of the windows old readings are averaged over
+    #[serde(
+        default = "default_average_interval",
+        deserialize_with = "deserialize_duration"
+    )]
+    pub average_interval: Duration,
+    /// How long the averages, transitions and alarms are kept
+    #[serde(
+        default = "default_average_retention",
+        deserialize_with = "deserialize_duration"
+    )]
+    pub average_retention: Duration,
+}
+
+impl Default for HistoryConfig {
+    fn default() -> Self {
+        Self {
+            path: default_history_path(),
+            raw_retention: default_raw_retention(),
+            average_interval: default_average_interval(),
+            average_retention: default_average_retention(),
+        }
+    }
+}
+
+impl HistoryConfig {
+    const DEFAULT_PATH: &'static str = "/var/lib/grobot/history.db";
+
+    pub fn validate(&self) -> Result<()> {
+        ensure!(
+            self.raw_retention > Duration::zero(),
+            "History raw retention must be longer than zero"
+        );
+        ensure!(
+            self.average_interval >= Duration::seconds(1),
+            "History average interval must be at least a second"
+        );
+        ensure!(
+            self.average_retention >= self.raw_retention,
+            "History average retention must be at least as long as the raw retention"
+        );
+
+        Ok(())
+    }
+}
+
+/// A reading, or the average of the readings over a window starting at `time`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Sample {
+    pub time: DateTime<Local>,
+    pub value: f64,
+}
+
+/// An on/off actuator being switched
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Transition {
+    pub time: DateTime<Local>,
+    pub actuator: Actuator,
+    pub on: bool,
+}
+
+/// An alarm being raised or cleared
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AlarmRecord {
+    pub time: DateTime<Local>,
+    pub alarm: Alarm,
+    pub raised: bool,
+}
+
+fn local(timestamp: i64) -> DateTime<Local> {
+    Local
+        .timestamp_opt(timestamp, 0)
+        .single()
+        .unwrap_or_default()
+}
+
+/// Readings, actuator transitions and alarms stored in SQLite
+pub struct History {
+    config: HistoryConfig,
+    connection: Mutex<Connection>,
+}
+
+impl History {
+    const SCHEMA: &'static str = "
+        CREATE TABLE IF NOT EXISTS readings (
+            time INTEGER NOT NULL,
+            metric TEXT NOT NULL,
+            value REAL NOT NULL
+        );
+        CREATE INDEX IF NOT EXISTS readings_metric_time ON readings (metric, time);
+        CREATE TABLE IF NOT EXISTS averages (
+            time INTEGER NOT NULL,
+            metric TEXT NOT NULL,
+            value REAL NOT NULL,
+            count INTEGER NOT NULL,
+            PRIMARY KEY (metric, time)
+        );
+        CREATE TABLE IF NOT EXISTS transitions (
+            time INTEGER NOT NULL,
+            actuator TEXT NOT NULL,
+            on_state INTEGER NOT NULL
+        );
+        CREATE INDEX IF NOT EXISTS transitions_time ON transitions (time);
+        CREATE TABLE IF NOT EXISTS alarms (
+            time INTEGER NOT NULL,
+            alarm TEXT NOT NULL,
+            raised INTEGER NOT NULL
+        );
+        CREATE INDEX IF NOT EXISTS alarms_time ON alarms (time);
+    ";
+
+    /// Open the database in the configured file, creating it if needed
+    pub fn open(config: &HistoryConfig) -> Result<Self> {
+        if let Some(dir) = config.path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+
+        Self::open_at(config, &config.path)
+    }
+
+    /// Open a database in a file, or in memory if the path is `:memory:`
+    pub fn open_at(config: &HistoryConfig, path: &Path) -> Result<Self> {
+        let connection = Connection::open(path)?;
+        // Let readers in while the controller writes, and spare the SD card a sync per row
+        connection.pragma_update(None, "journal_mode", "WAL")?;
+        connection.pragma_update(None, "synchronous", "NORMAL")?;
+        connection.execute_batch(Self::SCHEMA)?;
+
+        Ok(Self {
+            config: config.clone(),
+            connection: Mutex::new(connection),
+        })
+    }
+
+    /// Open an existing database to read from, failing if there isn't one at the path
+    pub fn open_read_only(config: &HistoryConfig, path: &Path) -> Result<Self> {
+        let connection = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+
+        Ok(Self {
+            config: config.clone(),
+            connection: Mutex::new(connection),
+        })
+    }
+
+    fn connection(&self) -> std::sync::MutexGuard<'_, Connection> {
+        // A panic mid-query leaves nothing half done that SQLite doesn't roll back
+        self.connection
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Record a reading of a metric like `temperature` or `soil/basil`
+    pub fn record(&self, time: &DateTime<Local>, metric: &str, value: f64) -> Result<()> {
+        self.connection().execute(
+            "INSERT INTO readings (time, metric, value) VALUES (?1, ?2, ?3)",
+            params![time.timestamp(), metric, value],
+        )?;
+
+        Ok(())
+    }
+
+    /// Record the thresholds in effect, so readings can be compared with what they were kept to
+    pub fn record_thresholds(
+        &self,
+        time: &DateTime<Local>,
+        thresholds: &ThresholdConfig,
+    ) -> Result<()> {
+        for (metric, value) in thresholds.metrics() {
+            self.record(time, &metric, value)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn record_transition(
+        &self,
+        time: &DateTime<Local>,
+        actuator: Actuator,
+        on: bool,
+    ) -> Result<()> {
+        self.connection().execute(
+            "INSERT INTO transitions (time, actuator, on_state) VALUES (?1, ?2, ?3)",
+            params![time.timestamp(), actuator.to_string(), on],
+        )?;
+
+        Ok(())
+    }
+
+    pub fn record_alarm(&self, time: &DateTime<Local>, alarm: &Alarm, raised: bool) -> Result<()> {
+        self.connection().execute(
+            "INSERT INTO alarms (time, alarm, raised) VALUES (?1, ?2, ?3)",
+            params![time.timestamp(), serde_json::to_string(alarm)?, raised],
+        )?;
+
+        Ok(())
+    }
+
+    /// Average the readings past the raw retention into windows, and drop whatever is past
+    /// the average retention
+    pub fn prune(&self, now: &DateTime<Local>) -> Result<()> {
+        let raw_cutoff = (*now - self.config.raw_retention).timestamp();
+        let average_cutoff = (*now - self.config.average_retention).timestamp();
+
+        let mut connection = self.connection();
+        let transaction = connection.transaction()?;
+
+        // A window the cutoff falls in is averaged in two goes, so merge by reading count
+        transaction.execute(
+            "INSERT INTO averages (time, metric, value, count)
+                SELECT time - time % ?1 AS start, metric, AVG(value), COUNT(*)
+                FROM readings WHERE time < ?2 GROUP BY metric, start
+                ON CONFLICT (metric, time) DO UPDATE SET
+                    value = (value * count + excluded.value * excluded.count)
+                        / (count + excluded.count),
+                    count = count + excluded.count",
+            params![self.config.average_interval.num_seconds(), raw_cutoff],
+        )?;
+        transaction.execute("DELETE FROM readings WHERE time < ?1", [raw_cutoff])?;
+
+        for table in ["averages", "transitions", "alarms"] {
+            transaction.execute(
+                &format!("DELETE FROM {} WHERE time < ?1", table),
+                [average_cutoff],
+            )?;
+        }
+
+        transaction.commit()?;
+
+        Ok(())
+    }
+
+    /// Names of the metrics with readings or averages
+    pub fn metrics(&self) -> Result<Vec<String>> {
+        let connection = self.connection();
+        let mut statement = connection.prepare(
+            "SELECT metric FROM readings UNION SELECT metric FROM averages ORDER BY metric",
+        )?;
+        let metrics = statement
+            .query_map([], |row| row.get(0))?
+            .collect::<Result<_, _>>()?;
+
+        Ok(metrics)
+    }
+
+    /// Readings of a metric from a time up to another, oldest first, with averages standing in
+    /// for the readings past the raw retention
+    pub fn samples(
+        &self,
+        metric: &str,
+        from: &DateTime<Local>,
+        to: &DateTime<Local>,
+    ) -> Result<Vec<Sample>> {
+        let connection = self.connection();
+        let mut statement = connection.prepare(
+            "SELECT time, value FROM averages WHERE metric = ?1 AND time >= ?2 AND time < ?3
+             UNION ALL
+             SELECT time, value FROM readings WHERE metric = ?1 AND time >= ?2 AND time < ?3
+             ORDER BY time",
+        )?;
+        let samples = statement
+            .query_map(params![metric, from.timestamp(), to.timestamp()], |row| {
+                Ok(Sample {
+                    time: local(row.get(0)?),
+                    value: row.get(1)?,
+                })
+            })?
+            .collect::<Result<_, _>>()?;
+
+        Ok(samples)
+    }
+
+    /// Averages of a metric over windows of an interval from a time up to another, each at the
+    /// start of its window, oldest first
+    pub fn aggregate(
+        &self,
+        metric: &str,
+        from: &DateTime<Local>,
+        to: &DateTime<Local>,
+        interval: Duration,
+    ) -> Result<Vec<Sample>> {
+        ensure!(
+            interval >= Duration::seconds(1),
+            "Aggregation interval must be at least a second"
+        );
+
+        let connection = self.connection();
+        let mut statement = connection.prepare(
+            "SELECT time - time % ?4 AS start, AVG(value) FROM (
+                 SELECT time, value FROM averages WHERE metric = ?1 AND time >= ?2 AND time < ?3
+                 UNION ALL
+                 SELECT time, value FROM readings WHERE metric = ?1 AND time >= ?2 AND time < ?3
+             ) GROUP BY start ORDER BY start",
+        )?;
+        let samples = statement
+            .query_map(
+                params![
+                    metric,
+                    from.timestamp(),
+                    to.timestamp(),
+                    interval.num_seconds()
+                ],
+                |row| {
+                    Ok(Sample {
+                        time: local(row.get(0)?),
+                        value: row.get(1)?,
+                    })
+                },
+            )?
+            .collect::<Result<_, _>>()?;
+
+        Ok(samples)
+    }
+
+    /// The last reading of a metric at or before a time, or average if only that is left
+    pub fn value_at(&self, metric: &str, time: &DateTime<Local>) -> Result<Option<f64>> {
+        let connection = self.connection();
+        let mut statement = connection.prepare(
+            "SELECT time, value FROM averages WHERE metric = ?1 AND time <= ?2
+             UNION ALL
+             SELECT time, value FROM readings WHERE metric = ?1 AND time <= ?2
+             ORDER BY time DESC LIMIT 1",
+        )?;
+        let mut rows = statement.query(params![metric, time.timestamp()])?;
+
+        Ok(match rows.next()? {
+            Some(row) => Some(row.get(1)?),
+            None => None,
+        })
+    }
+
+    /// Whether an actuator was on at a time, going by the last time it was switched before
+    pub fn state_at(&self, actuator: Actuator, time: &DateTime<Local>) -> Result<Option<bool>> {
+        let connection = self.connection();
+        let mut statement = connection.prepare(
+            "SELECT on_state FROM transitions WHERE actuator = ?1 AND time <= ?2
+             ORDER BY time DESC, rowid DESC LIMIT 1",
+        )?;
+        let mut rows = statement.query(params![actuator.to_string(), time.timestamp()])?;
+
+        Ok(match rows.next()? {
+            Some(row) => Some(row.get(0)?),
+            None => None,
+        })
+    }
+
+    /// Actuator transitions from a time up to another, oldest first
+    pub fn transitions(
+        &self,
+        from: &DateTime<Local>,
+        to: &DateTime<Local>,
+    ) -> Result<Vec<Transition>> {
+        let connection = self.connection();
+        let mut statement = connection.prepare(
+            "SELECT time, actuator, on_state FROM transitions
+             WHERE time >= ?1 AND time < ?2 ORDER BY time, rowid",
+        )?;
+        let rows = statement
+            .query_map(params![from.timestamp(), to.timestamp()], |row| {
+                Ok((row.get(0)?, row.get::<_, String>(1)?, row.get(2)?))
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        rows.into_iter()
+            .map(|(time, actuator, on)| {
+                Ok(Transition {
+                    time: local(time),
+                    actuator: actuator.parse()?,
+                    on,
+                })
+            })
+            .collect()
+    }
+
+    /// Alarms raised or cleared from a time up to another, oldest first
+    pub fn alarms(&self, from: &DateTime<Local>, to: &DateTime<Local>) -> Result<Vec<AlarmRecord>> {
+        let connection = self.connection();
+        let mut statement = connection.prepare(
+            "SELECT time, alarm, raised FROM alarms WHERE time >= ?1 AND time < ?2 ORDER BY time, rowid",
+        )?;
+        let rows = statement
+            .query_map(params![from.timestamp(), to.timestamp()], |row| {
+                Ok((row.get(0)?, row.get::<_, String>(1)?, row.get(2)?))
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        rows.into_iter()
+            .map(|(time, alarm, raised)| {
+                Ok(AlarmRecord {
+                    time: local(time),
+                    alarm: serde_json::from_str(&alarm)?,
+                    raised,
+                })
+            })
+            .collect()
+    }
+}
@@ -1,19 +1,70 @@
-use anyhow::{ensure, Context, Error, Result};
-use chrono::{DateTime, Local, NaiveDateTime, TimeZone};
+use anyhow::{bail, ensure, Context, Error, Result};
+use chrono::{DateTime, Datelike, Duration, Local, NaiveDate, NaiveTime, Weekday};
 use dht22_pi::{read as dht22_read, Reading};
 use ringbuffer::{AllocRingBuffer, RingBuffer, RingBufferExt, RingBufferWrite};
-use rppal::{gpio::OutputPin, pwm::Pwm};
+use rppal::{
+    gpio::{InputPin, OutputPin, Trigger},
+    pwm::Pwm,
+};
 use serde::{Deserialize, Serialize};
-use serde_json::to_string;
-use std::{collections::HashMap, path::Path};
+use std::{
+    collections::{HashMap, HashSet},
+    path::Path,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Instant,
+};
 use tokio::{fs::File, io::AsyncReadExt};
 use toml::from_str;
 use tracing::{info, warn};
 
+pub mod api;
+pub mod auth;
+pub mod camera;
+pub mod co2;
+pub mod command;
+pub mod dli;
+pub mod export;
+pub mod history;
+pub mod metrics;
+pub mod mqtt;
+pub mod pump;
+pub mod soil;
+pub mod state;
+pub mod stream;
+pub mod sun;
+
+pub use api::{Api, HistoryEntry, SharedApi};
+pub use auth::{AuthConfig, Authenticator, Envelope};
+pub use camera::{CameraConfig, Snapshot};
+pub use co2::{Co2Sensor, Co2SensorConfig};
+pub use command::{Command, Override};
+pub use dli::{DliTracker, LightSensor, LightSensorConfig};
+pub use export::Export;
+pub use history::{AlarmRecord, History, HistoryConfig, Sample, Transition};
+pub use metrics::Metrics;
+pub use mqtt::{MqttConfig, Topics};
+pub use pump::{PumpConfig, PumpLedger};
+pub use soil::{Ads1115, Soil, SoilConfig, SoilSensorConfig};
+pub use state::State;
+pub use stream::StreamEvent;
+pub use sun::Location;
+
 pub const PORT: u16 = 8332;
+/// Port the controller listens for commands on
+pub const COMMAND_PORT: u16 = 8333;
+/// Port the controller serves its HTTP API on
+pub const HTTP_PORT: u16 = 8080;
+
+/// Light level of a dimmable light at full power, in percent
+pub const FULL_LEVEL: f64 = 100.0;
 
 pub struct Environment {
     readings: AllocRingBuffer<Reading>,
+    co2: AllocRingBuffer<f32>,
 }
 
 impl Default for Environment {
@@ -24,24 +75,45 @@ impl Default for Environment {
 
 impl Environment {
     const DEFAULT_INITIAL_READINGS: usize = 8;
+    /// Top of the range of the sensors we support, anything above is a bad reading
+    const MAX_CO2: f32 = 40_000.0;
 
-    pub fn json(&self) -> Result<String> {
-        let temp = self.temp();
-        let humidity = self.humidity();
+    pub fn with_readings(initial_readings: usize) -> Self {
+        Self {
+            readings: AllocRingBuffer::with_capacity(initial_readings),
+            co2: AllocRingBuffer::with_capacity(initial_readings),
+        }
+    }
 
-        let content: HashMap<String, f32> = HashMap::from_iter([
-            ("temp".to_string(), temp),
-            ("humidity".to_string(), humidity),
-        ]);
+    /// Whether there are any temperature and humidity readings to go on
+    pub fn has_readings(&self) -> bool {
+        !self.readings.is_empty()
+    }
 
-        let json = to_string(&content)?;
+    /// The raw temperature (C) and humidity readings, oldest first
+    pub fn readings(&self) -> Vec<(f32, f32)> {
+        self.readings
+            .iter()
+            .map(|r| (r.temperature, r.humidity))
+            .collect()
+    }
 
-        Ok(json)
+    /// The CO2 readings in ppm, oldest first
+    pub fn co2_readings(&self) -> Vec<f32> {
+        self.co2.iter().copied().collect()
     }
 
-    pub fn with_readings(initial_readings: usize) -> Self {
-        Self {
-            readings: AllocRingBuffer::with_capacity(initial_readings),
+    /// Pick up from readings saved before a restart
+    pub fn restore(&mut self, readings: &[(f32, f32)], co2: &[f32]) {
+        for (temperature, humidity) in readings {
+            self.add_reading(Reading {
+                temperature: *temperature,
+                humidity: *humidity,
+            });
+        }
+
+        for co2 in co2 {
+            self.add_co2(*co2);
         }
     }
 
@@ -58,15 +130,24 @@ impl Environment {
         Ok(())
     }
 
-    /// Do a single reading from the sensor,
-    pub async fn read(&mut self, pin: u8) {
-        if let Ok(reading) = dht22_read(pin) {
-            self.add_reading(reading);
-        } else {
-            warn!("Failed to read from sensor");
+    /// Do a single reading from the sensor, returning whether it gave a good reading
+    pub async fn read(&mut self, pin: u8) -> bool {
+        match dht22_read(pin) {
+            Ok(reading) => self.add_reading(reading),
+            Err(e) => {
+                warn!("Failed to read from sensor: {:?}", e);
+                false
+            }
         }
     }
 
+    /// The latest raw temperature (F) and humidity reading
+    pub fn latest(&self) -> Option<(f32, f32)> {
+        self.readings
+            .back()
+            .map(|r| (self.ctof(r.temperature), r.humidity))
+    }
+
     fn ctof(&self, c: f32) -> f32 {
         (c * (9.0 / 5.0)) + 32.0
     }
@@ -124,7 +205,28 @@ impl Environment {
         humidity
     }
 
-    pub fn add_reading(&mut self, reading: Reading) {
+    /// Retrieve the CO2 concentration in ppm, if there are any CO2 readings
+    pub fn co2(&self) -> Option<f32> {
+        if self.co2.is_empty() {
+            return None;
+        }
+
+        let co2 = self.co2.iter().sum::<f32>() / self.co2.len() as f32;
+
+        info!("Cleaned CO2 reading: {}ppm", co2);
+
+        Some(co2)
+    }
+
+    pub fn add_co2(&mut self, co2: f32) {
+        if (0.0..=Environment::MAX_CO2).contains(&co2) {
+            info!("Added new CO2 reading: {}ppm", co2);
+            self.co2.push(co2);
+        }
+    }
+
+    /// Add a reading, returning whether it was good enough to keep
+    pub fn add_reading(&mut self, reading: Reading) -> bool {
         if reading.humidity >= 0.0
             && reading.humidity <= 100.0
             && !reading.temperature.is_nan()
@@ -132,6 +234,10 @@ impl Environment {
         {
             info!("Added new sensor reading: {:?}", reading);
             self.readings.push(reading);
+            true
+        } else {
+            warn!("Dropped bad sensor reading: {:?}", reading);
+            false
         }
     }
 }
@@ -172,9 +278,10 @@ impl FanPower {
     }
 }
 
-pub struct Light(OutputPin);
+/// An output on one channel of the relay board. The relays are active low.
+pub struct Relay(OutputPin);
 
-impl Light {
+impl Relay {
     pub fn new(pin: OutputPin) -> Self {
         Self(pin)
     }
@@ -188,22 +295,33 @@ impl Light {
     }
 }
 
-pub struct Mist(OutputPin);
+pub type Light = Relay;
 
-impl Mist {
-    pub fn new(pin: OutputPin) -> Self {
-        Self(pin)
+pub struct DimmableLight(Pwm);
+
+impl DimmableLight {
+    pub fn new(pwm: Pwm) -> Self {
+        Self(pwm)
     }
 
-    pub fn on(&mut self) {
-        self.0.set_low();
+    /// Set the light level as a percentage of full power
+    pub fn set_level(&mut self, level: f64) -> Result<()> {
+        self.0
+            .set_duty_cycle((level / FULL_LEVEL).clamp(0.0, 1.0))?;
+        Ok(())
     }
 
-    pub fn off(&mut self) {
-        self.0.set_high();
+    pub fn off(&mut self) -> Result<()> {
+        self.0.set_duty_cycle(0.0)?;
+        Ok(())
     }
 }
 
+pub type Mist = Relay;
+
+/// The pump dispenses a volume by running for the time its flow rate calls for
+pub type Pump = Relay;
+
 pub struct Fan((Pwm, FanPower));
 
 impl Fan {
@@ -222,62 +340,539 @@ impl Fan {
     }
 }
 
+/// Counts pulses from a fan's tachometer output to measure its speed
+pub struct Tachometer {
+    // Kept so the interrupt stays registered
+    _pin: InputPin,
+    pulses: Arc<AtomicU64>,
+    last: (Instant, u64),
+}
+
+impl Tachometer {
+    /// Noctua (and most PC) fans pulse twice per revolution
+    const PULSES_PER_REVOLUTION: f64 = 2.0;
+
+    /// The tachometer output is open collector, so the pin should be pulled up
+    pub fn new(mut pin: InputPin) -> Result<Self> {
+        let pulses = Arc::new(AtomicU64::new(0));
+        let counter = pulses.clone();
+
+        pin.set_async_interrupt(Trigger::FallingEdge, move |_| {
+            counter.fetch_add(1, Ordering::Relaxed);
+        })?;
+
+        Ok(Self {
+            _pin: pin,
+            pulses,
+            last: (Instant::now(), 0),
+        })
+    }
+
+    /// The average speed of the fan since the last call, in RPM
+    pub fn rpm(&mut self) -> f64 {
+        let now = Instant::now();
+        let pulses = self.pulses.load(Ordering::Relaxed);
+        let (last_time, last_pulses) = self.last;
+        self.last = (now, pulses);
+
+        let minutes = now.duration_since(last_time).as_secs_f64() / 60.0;
+
+        if minutes > 0.0 {
+            (pulses - last_pulses) as f64 / Self::PULSES_PER_REVOLUTION / minutes
+        } else {
+            0.0
+        }
+    }
+}
+
+/// Which logic level means a switch input is active
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SwitchLevel {
+    High,
+    /// Switches are usually wired to pull a pulled-up pin to ground
+    #[default]
+    Low,
+}
+
+/// A switch input like a float switch or reed switch
+#[derive(Deserialize, Debug, Clone)]
+pub struct SwitchConfig {
+    /// Pin the switch is connected to, which is pulled up
+    pub pin: u8,
+    /// Level of the pin when the switch is active
+    #[serde(default)]
+    pub active: SwitchLevel,
+}
+
+pub struct Switch {
+    pin: InputPin,
+    active: SwitchLevel,
+}
+
+impl Switch {
+    pub fn new(pin: InputPin, active: SwitchLevel) -> Self {
+        Self { pin, active }
+    }
+
+    pub fn is_active(&self) -> bool {
+        match self.active {
+            SwitchLevel::High => self.pin.is_high(),
+            SwitchLevel::Low => self.pin.is_low(),
+        }
+    }
+}
+
+/// Tracks whether the cabinet door is open, and when it was last closed
+#[derive(Debug, Clone, Default)]
+pub struct DoorState {
+    open: bool,
+    closed_at: Option<DateTime<Local>>,
+}
+
+impl DoorState {
+    pub fn open(&mut self) {
+        self.open = true;
+    }
+
+    pub fn close(&mut self, time: DateTime<Local>) {
+        self.open = false;
+        self.closed_at = Some(time);
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.open
+    }
+
+    /// Whether decisions based on the environment are suspended at the given time, because
+    /// the door is open or was closed less than `settle` ago
+    pub fn suspended(&self, settle: Duration, time: &DateTime<Local>) -> bool {
+        self.open
+            || self
+                .closed_at
+                .is_some_and(|closed_at| *time - closed_at < settle)
+    }
+}
+
+/// Problems that need someone to come look at the cabinet
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Alarm {
+    /// A fan is not spinning even though it is being driven
+    FanStalled { fan: usize },
+    /// The mister's reservoir is low, so the mister is locked out until it is refilled
+    ReservoirLow,
+}
+
+impl std::fmt::Display for Alarm {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Alarm::FanStalled { fan } => write!(f, "fan {} stalled", fan),
+            Alarm::ReservoirLow => write!(f, "refill reservoir"),
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Actuator {
+    Light,
+    Mist,
+    Fan,
+}
+
+impl Actuator {
+    pub const ALL: [Actuator; 3] = [Actuator::Light, Actuator::Mist, Actuator::Fan];
+}
+
+impl std::fmt::Display for Actuator {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Actuator::Light => "light",
+            Actuator::Mist => "mist",
+            Actuator::Fan => "fan",
+        };
+
+        write!(f, "{}", name)
+    }
+}
+
+impl FromStr for Actuator {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "light" => Ok(Actuator::Light),
+            "mist" => Ok(Actuator::Mist),
+            "fan" => Ok(Actuator::Fan),
+            _ => bail!("Unknown actuator '{}', expected light, mist or fan", s),
+        }
+    }
+}
+
 #[derive(Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
 pub enum Action {
     On,
     Off,
 }
 
+/// Parse a duration like `30m`, `1h`, `90s`, `7d` or `1h30m`
+pub fn parse_duration(s: &str) -> Result<Duration> {
+    let mut duration = Duration::zero();
+    let mut digits = String::new();
+
+    for c in s.trim().chars() {
+        if c.is_ascii_digit() {
+            digits.push(c);
+            continue;
+        }
+
+        let value: u64 = digits
+            .parse()
+            .with_context(|| format!("Expected a number before '{}' in duration '{}'", c, s))?;
+
+        let unit = match c {
+            'd' => 24 * 60 * 60,
+            'h' => 60 * 60,
+            'm' => 60,
+            's' => 1,
+            _ => bail!("Unknown unit '{}' in duration '{}'", c, s),
+        };
+
+        duration = value
+            .checked_mul(unit)
+            .and_then(|seconds| Duration::from_std(std::time::Duration::from_secs(seconds)).ok())
+            .and_then(|part| duration.checked_add(&part))
+            .with_context(|| format!("Duration '{}' is too long", s))?;
+
+        digits.clear();
+    }
+
+    ensure!(
+        digits.is_empty(),
+        "Duration '{}' must end with a unit (d, h, m or s)",
+        s
+    );
+
+    Ok(duration)
+}
+
+// Parse a duration string like `30m`
+fn deserialize_duration<'de, D>(deserializer: D) -> Result<Duration, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let s = String::deserialize(deserializer)?;
+    parse_duration(&s).map_err(serde::de::Error::custom)
+}
+
+// Parse an optional duration string like `30m`
+fn parse_optional_duration<'de, D>(deserializer: D) -> Result<Option<Duration>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let s = String::deserialize(deserializer)?;
+    parse_duration(&s)
+        .map(Some)
+        .map_err(serde::de::Error::custom)
+}
+
+// Parse an optional time string in %H:%M format
+fn parse_time_of_day<'de, D>(deserializer: D) -> Result<Option<NaiveTime>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let s = String::deserialize(deserializer)?;
+    NaiveTime::parse_from_str(&s, "%H:%M")
+        .map(Some)
+        .map_err(serde::de::Error::custom)
+}
+
+/// When an event happens during the day
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TimeSpec {
+    /// A fixed local time, written as `%H:%M`
+    Fixed(NaiveTime),
+    /// An offset from sunrise, written as `sunrise`, `sunrise+30m` or `sunrise-1h`
+    Sunrise(Duration),
+    /// An offset from sunset, written as `sunset`, `sunset+30m` or `sunset-1h`
+    Sunset(Duration),
+}
+
+impl FromStr for TimeSpec {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let s = s.trim();
+
+        for (name, solar) in [
+            ("sunrise", TimeSpec::Sunrise as fn(Duration) -> TimeSpec),
+            ("sunset", TimeSpec::Sunset),
+        ] {
+            if let Some(offset) = s.strip_prefix(name) {
+                let offset = offset.trim();
+
+                let offset = if offset.is_empty() {
+                    Duration::zero()
+                } else if let Some(offset) = offset.strip_prefix('+') {
+                    parse_duration(offset)?
+                } else if let Some(offset) = offset.strip_prefix('-') {
+                    -parse_duration(offset)?
+                } else {
+                    bail!("Expected '+' or '-' after '{}' in '{}'", name, s);
+                };
+
+                return Ok(solar(offset));
+            }
+        }
+
+        Ok(TimeSpec::Fixed(NaiveTime::parse_from_str(s, "%H:%M")?))
+    }
+}
+
+impl TimeSpec {
+    /// Resolve to a local time of day on the given date. Returns `None` for a solar event when
+    /// there is no location or the sun does not rise or set on that date. An offset that would
+    /// take a solar event into the next or previous day stops at the end or start of this one.
+    pub fn resolve(&self, date: NaiveDate, location: Option<&Location>) -> Option<NaiveTime> {
+        let (base, offset) = match self {
+            TimeSpec::Fixed(time) => return Some(*time),
+            TimeSpec::Sunrise(offset) => (location?.sunrise_sunset(date)?.0, offset),
+            TimeSpec::Sunset(offset) => (location?.sunrise_sunset(date)?.1, offset),
+        };
+
+        let time = base.checked_add_signed(*offset)?.with_timezone(&Local);
+
+        // Wrapping around would put an Off before its On and drop the window
+        if time.date_naive() > date {
+            NaiveTime::from_hms_opt(23, 59, 59)
+        } else if time.date_naive() < date {
+            NaiveTime::from_hms_opt(0, 0, 0)
+        } else {
+            Some(time.time())
+        }
+    }
+
+    pub fn is_solar(&self) -> bool {
+        !matches!(self, TimeSpec::Fixed(_))
+    }
+}
+
 #[derive(Deserialize, Debug, Clone)]
 pub struct Event {
     #[serde(deserialize_with = "Event::parse_time")]
-    time: DateTime<Local>,
+    time: TimeSpec,
     action: Action,
+    /// Days of the week this event happens on, or every day if not given
+    #[serde(default)]
+    days: Option<Vec<Weekday>>,
+    /// Light level in percent an On event brings a dimmable light to, full if not given
+    #[serde(default)]
+    level: Option<f64>,
+    /// How long a dimmable light takes to fade to the event's level (or out, for Off)
+    #[serde(default, deserialize_with = "parse_optional_duration")]
+    fade: Option<Duration>,
 }
 
 impl Event {
-    // Parse a time string in %H:%M format with strftime
-    fn parse_time<'de, D>(deserializer: D) -> Result<DateTime<Local>, D::Error>
+    /// Whether this event happens on the given date
+    pub fn occurs_on(&self, date: NaiveDate) -> bool {
+        self.days
+            .as_ref()
+            .is_none_or(|days| days.contains(&date.weekday()))
+    }
+
+    // Parse a time string in %H:%M format, or relative to sunrise/sunset
+    fn parse_time<'de, D>(deserializer: D) -> Result<TimeSpec, D::Error>
     where
         D: serde::Deserializer<'de>,
     {
-        let now = Local::now();
-        let now_date = now.format("%Y-%m-%d").to_string();
         let s = String::deserialize(deserializer)?;
-        let time = NaiveDateTime::parse_from_str(&format!("{}T{}", now_date, s), "%Y-%m-%dT%H:%M")
-            .map_err(serde::de::Error::custom)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
 
-        let local = Local.from_local_datetime(&time).single().ok_or_else(|| {
-            serde::de::Error::custom(format!("Failed to convert {} to local time", time))
-        })?;
+/// A compact way to write a window that repeats through the day, like "10 minutes every 2
+/// hours". These are expanded into on/off events by `Config::setup`.
+#[derive(Deserialize, Debug, Clone)]
+pub struct Repeat {
+    /// How often the window starts
+    #[serde(deserialize_with = "deserialize_duration")]
+    every: Duration,
+    /// How long the window lasts
+    #[serde(rename = "for", deserialize_with = "deserialize_duration")]
+    length: Duration,
+    /// Start of the first window, midnight if not given
+    #[serde(default, deserialize_with = "parse_time_of_day")]
+    starting: Option<NaiveTime>,
+    /// No window starts or runs past this time, the end of the day if not given
+    #[serde(default, deserialize_with = "parse_time_of_day")]
+    until: Option<NaiveTime>,
+    /// Days of the week the windows happen on, or every day if not given
+    #[serde(default)]
+    days: Option<Vec<Weekday>>,
+}
+
+impl Repeat {
+    /// Expand into pairs of on/off events
+    pub fn expand(&self) -> Result<Vec<Event>> {
+        ensure!(
+            self.every > Duration::zero(),
+            "Repeat interval must be longer than zero"
+        );
+        ensure!(
+            self.length > Duration::zero() && self.length <= self.every,
+            "Repeat length must be longer than zero and no longer than the interval"
+        );
+
+        let starting = self.starting.unwrap_or(NaiveTime::MIN);
+        let until = self
+            .until
+            .or_else(|| NaiveTime::from_hms_opt(23, 59, 59))
+            .context("Invalid end of day")?;
+
+        ensure!(starting < until, "Repeat must start before it ends");
+
+        let mut events = Vec::new();
+        let mut on = starting;
+
+        while on < until {
+            // Stop at the end time rather than wrapping around past midnight
+            let (off, wrapped) = on.overflowing_add_signed(self.length);
+            let off = if wrapped != 0 { until } else { off.min(until) };
+
+            for (time, action) in [(on, Action::On), (off, Action::Off)] {
+                events.push(Event {
+                    time: TimeSpec::Fixed(time),
+                    action,
+                    days: self.days.clone(),
+                    level: None,
+                    fade: None,
+                });
+            }
 
-        Ok(local)
+            let (next, wrapped) = on.overflowing_add_signed(self.every);
+
+            if wrapped != 0 {
+                break;
+            }
+
+            on = next;
+        }
+
+        Ok(events)
     }
 }
 
+fn default_stall_threshold() -> f64 {
+    FanConfig::DEFAULT_STALL_THRESHOLD
+}
+
 #[derive(Deserialize, Debug, Clone)]
 pub struct FanConfig {
     #[serde(deserialize_with = "FanPower::parse_fan_power")]
     power: FanPower,
+    /// Pins the fans' tachometer outputs are connected to, one per fan
+    #[serde(default)]
+    tach_pins: Vec<u8>,
+    /// Fan power in percent above which a fan reading 0 RPM is considered stalled
+    #[serde(default = "default_stall_threshold")]
+    stall_threshold: f64,
+    #[serde(default)]
     schedule: Vec<Event>,
+    #[serde(default)]
+    repeat: Vec<Repeat>,
+}
+
+/// Settings for a grow light driver with PWM (or PWM to 0-10V) dimming
+#[derive(Deserialize, Debug, Clone)]
+pub struct DimmingConfig {
+    /// PWM frequency the driver expects, in Hz
+    pub frequency: f64,
+    /// Invert the PWM signal, for drivers that dim as the duty cycle goes up
+    #[serde(default)]
+    pub invert: bool,
+}
+
+/// Settings for tracking the daily light integral (DLI) the plants get
+#[derive(Deserialize, Debug, Clone)]
+pub struct DliConfig {
+    /// PPFD at the canopy with the light at full power, in umol/m^2/s
+    pub ppfd: f64,
+    /// DLI to reach each day in mol/m^2/day. If the schedule falls short, the light stays on
+    /// after the last scheduled window until the target is reached.
+    pub target: Option<f64>,
+    /// Latest time the light can stay on to reach the target, the end of the day if not given
+    #[serde(default, deserialize_with = "parse_time_of_day")]
+    pub until: Option<NaiveTime>,
+    /// Light sensor to measure PPFD with instead of estimating it from the light level
+    pub sensor: Option<LightSensorConfig>,
+}
+
+impl FanConfig {
+    const DEFAULT_STALL_THRESHOLD: f64 = 20.0;
 }
 
 #[derive(Deserialize, Debug, Clone)]
 pub struct LightConfig {
+    dimming: Option<DimmingConfig>,
+    dli: Option<DliConfig>,
+    #[serde(default)]
     schedule: Vec<Event>,
+    #[serde(default)]
+    repeat: Vec<Repeat>,
 }
 
 #[derive(Deserialize, Debug, Clone)]
 pub struct MistConfig {
+    /// Float switch or level sensor that is active when the reservoir is low. The mister
+    /// never runs while it is active so it doesn't run dry.
+    reservoir: Option<SwitchConfig>,
+    #[serde(default)]
     schedule: Vec<Event>,
+    #[serde(default)]
+    repeat: Vec<Repeat>,
 }
 
+/// A reed switch on the cabinet door
 #[derive(Deserialize, Debug, Clone)]
+pub struct DoorConfig {
+    #[serde(flatten)]
+    switch: SwitchConfig,
+    /// Actuators to turn off while the door is open
+    #[serde(default)]
+    pause: Vec<Actuator>,
+    /// How long to wait after the door closes before the environment is used again
+    #[serde(default, deserialize_with = "parse_optional_duration")]
+    settle: Option<Duration>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ThresholdConfig {
     min_temp: f32,
     min_humidity: f32,
     max_temp: f32,
     max_humidity: f32,
+    /// CO2 in ppm below which the fan runs to exchange air with the room
+    min_co2: Option<f32>,
+}
+
+impl ThresholdConfig {
+    /// Prefix of the history metrics the thresholds are recorded as
+    pub const METRIC_PREFIX: &'static str = "threshold/";
+
+    /// The thresholds set, as history metrics like `threshold/min_temp` and their values
+    pub fn metrics(&self) -> Vec<(String, f64)> {
+        [
+            ("min_temp", Some(self.min_temp)),
+            ("min_humidity", Some(self.min_humidity)),
+            ("max_temp", Some(self.max_temp)),
+            ("max_humidity", Some(self.max_humidity)),
+            ("min_co2", self.min_co2),
+        ]
+        .into_iter()
+        .filter_map(|(name, value)| {
+            Some((format!("{}{}", Self::METRIC_PREFIX, name), value? as f64))
+        })
+        .collect()
+    }
 }
 
 #[derive(Deserialize, Debug, Clone)]
@@ -286,21 +881,166 @@ pub struct Config {
     light: LightConfig,
     mist: MistConfig,
     thresholds: ThresholdConfig,
+    location: Option<Location>,
+    door: Option<DoorConfig>,
+    soil: Option<SoilConfig>,
+    pump: Option<PumpConfig>,
+    co2: Option<Co2SensorConfig>,
+    camera: Option<CameraConfig>,
+    auth: Option<AuthConfig>,
+    mqtt: Option<MqttConfig>,
+    #[serde(default)]
+    history: HistoryConfig,
+}
+
+/// A window of time a schedule is on for, made of one or more overlapping On events and
+/// the Off event that closes them
+struct Window<'a> {
+    ons: Vec<(NaiveTime, &'a Event)>,
+    off: (NaiveTime, &'a Event),
+}
+
+impl Window<'_> {
+    fn on(&self) -> NaiveTime {
+        self.ons[0].0
+    }
+
+    fn contains(&self, time: NaiveTime) -> bool {
+        self.on() <= time && self.off.0 > time
+    }
+
+    /// The light level at a time in the window in percent. Each On event fades from the
+    /// level the window was at to its own level, and the Off event fades out to nothing.
+    fn level(&self, time: NaiveTime) -> f64 {
+        let mut level = 0.0;
+
+        for (on, event) in self.ons.iter().take_while(|(on, _)| *on <= time) {
+            let target = event.level.unwrap_or(FULL_LEVEL);
+            level = match event.fade {
+                Some(fade) if time - *on < fade => {
+                    level + (target - level) * fraction(time - *on, fade)
+                }
+                _ => target,
+            };
+        }
+
+        let (off, event) = self.off;
+
+        match event.fade {
+            Some(fade) if off - time < fade => level * fraction(off - time, fade),
+            _ => level,
+        }
+    }
+}
+
+/// How far through a fade we are, from 0.0 to 1.0
+fn fraction(elapsed: Duration, fade: Duration) -> f64 {
+    elapsed.num_milliseconds() as f64 / fade.num_milliseconds() as f64
 }
 
 impl Config {
+    /// Resolve a schedule for the given date into sorted windows of local times.
+    ///
+    /// Events that don't happen on the date are skipped. Overlapping windows (for example a
+    /// weekly window on top of a daily one) are merged, so the schedule stays on until every
+    /// window that is open has been closed.
+    fn resolve_windows<'a>(&self, schedule: &'a [Event], date: NaiveDate) -> Vec<Window<'a>> {
+        let mut events = schedule
+            .iter()
+            .filter(|e| e.occurs_on(date))
+            .filter_map(|e| Some((e.time.resolve(date, self.location.as_ref())?, e)))
+            .collect::<Vec<_>>();
+
+        // On events sort before Off events at the same time so adjacent windows merge
+        events.sort_by_key(|(time, event)| (*time, event.action == Action::Off));
+
+        let mut windows = Vec::new();
+        let mut open = 0;
+        let mut ons = Vec::new();
+
+        for (time, event) in events {
+            match event.action {
+                Action::On => {
+                    open += 1;
+                    ons.push((time, event));
+                }
+                Action::Off if open > 0 => {
+                    open -= 1;
+
+                    if open == 0 {
+                        windows.push(Window {
+                            ons: std::mem::take(&mut ons),
+                            off: (time, event),
+                        });
+                    }
+                }
+                Action::Off => {}
+            }
+        }
+
+        windows
+    }
+
+    /// The (on, off) windows an actuator is scheduled to be on for the given date
+    pub fn windows(&self, actuator: Actuator, date: NaiveDate) -> Vec<(NaiveTime, NaiveTime)> {
+        let schedule = match actuator {
+            Actuator::Light => &self.light.schedule,
+            Actuator::Mist => &self.mist.schedule,
+            Actuator::Fan => &self.fan.schedule,
+        };
+
+        self.resolve_windows(schedule, date)
+            .iter()
+            .map(|w| (w.on(), w.off.0))
+            .collect()
+    }
+
+    /// The first time after `time` that any schedule changes an actuator or doses the pump,
+    /// looking as far ahead as the end of the next day
+    pub fn next_transition(&self, time: &DateTime<Local>) -> Option<DateTime<Local>> {
+        let today = time.date_naive();
+        let location = self.location.as_ref();
+
+        [today, today.succ_opt()?]
+            .into_iter()
+            .flat_map(|date| {
+                self.light
+                    .schedule
+                    .iter()
+                    .chain(&self.mist.schedule)
+                    .chain(&self.fan.schedule)
+                    .filter(move |e| e.occurs_on(date))
+                    .filter_map(move |e| e.time.resolve(date, location))
+                    .chain(
+                        self.pump
+                            .iter()
+                            .flat_map(move |pump| pump.dose_times(date, location)),
+                    )
+                    .filter_map(move |t| date.and_time(t).and_local_timezone(Local).earliest())
+            })
+            .filter(|t| t > time)
+            .min()
+    }
+
+    /// Check if the time is between any of the on/off pairs of the schedule
+    fn scheduled(&self, schedule: &[Event], time: &DateTime<Local>) -> bool {
+        self.resolve_windows(schedule, time.date_naive())
+            .iter()
+            .any(|w| w.contains(time.time()))
+    }
+
+    /// Check if the light should be on at the given time by the schedule alone
+    pub fn light_on_schedule(&mut self, time: &DateTime<Local>) -> bool {
+        self.scheduled(&self.light.schedule, time)
+    }
+
     pub fn light_on(&mut self, time: &DateTime<Local>, environment: (f32, f32)) -> bool {
         let (temp, humidity) = environment;
         // Check if the light should be on at the given time by:
-        // * Sorting the schedule by time
+        // * Resolving the schedule to times of day and sorting them
         // * Bucketing the schedule into pairs of on/off events
         // * Checking if the time is between any of the on/off pairs
-        let light_on_schedule = self.light.schedule.chunks(2).any(|pair| {
-            let on = &pair[0];
-            let off = &pair[1];
-
-            on.time.time() <= time.time() && off.time.time() > time.time()
-        });
+        let light_on_schedule = self.scheduled(&self.light.schedule, time);
 
         // Check if the light should be on due to the humidity
         // If humidity is too high, we turn on to burn off the excess
@@ -318,18 +1058,86 @@ impl Config {
         !self.light_on(time, environment)
     }
 
+    /// The level in percent a dimmable light should be at. Scheduled windows fade in and out
+    /// as configured, and the light runs at full power when it is on for the environment.
+    pub fn light_level(&mut self, time: &DateTime<Local>, environment: (f32, f32)) -> f64 {
+        if !self.light_on(time, environment) {
+            return 0.0;
+        }
+
+        self.window_level(time).unwrap_or(FULL_LEVEL)
+    }
+
+    /// The level in percent a dimmable light should be at by the schedule alone
+    pub fn light_level_schedule(&mut self, time: &DateTime<Local>) -> f64 {
+        self.window_level(time).unwrap_or(0.0)
+    }
+
+    /// The level of the scheduled light window the time is in, if there is one
+    fn window_level(&self, time: &DateTime<Local>) -> Option<f64> {
+        self.resolve_windows(&self.light.schedule, time.date_naive())
+            .iter()
+            .find(|w| w.contains(time.time()))
+            .map(|w| w.level(time.time()))
+    }
+
+    pub fn light_dimming(&self) -> Option<&DimmingConfig> {
+        self.light.dimming.as_ref()
+    }
+
+    pub fn light_dli(&self) -> Option<&DliConfig> {
+        self.light.dli.as_ref()
+    }
+
+    /// Check if the light should stay on past the end of the schedule to reach the target DLI
+    pub fn light_compensate(
+        &mut self,
+        time: &DateTime<Local>,
+        environment: (f32, f32),
+        dli: f64,
+    ) -> bool {
+        let (temp, _) = environment;
+
+        let Some(config) = &self.light.dli else {
+            return false;
+        };
+
+        let Some(target) = config.target else {
+            return false;
+        };
+
+        // Only extend the end of the day, after the last scheduled window has closed
+        let end_of_schedule = self
+            .resolve_windows(&self.light.schedule, time.date_naive())
+            .last()
+            .map(|w| w.off.0);
+
+        let until = config
+            .until
+            .or_else(|| NaiveTime::from_hms_opt(23, 59, 59))
+            .unwrap_or(NaiveTime::MIN);
+
+        // Never extend if it is too hot for the light
+        let light_off_environment = temp > self.thresholds.max_temp;
+
+        dli < target
+            && end_of_schedule.is_some_and(|end| end <= time.time())
+            && time.time() < until
+            && !light_off_environment
+    }
+
+    /// Check if the mist should be on at the given time by the schedule alone
+    pub fn mist_on_schedule(&mut self, time: &DateTime<Local>) -> bool {
+        self.scheduled(&self.mist.schedule, time)
+    }
+
     pub fn mist_on(&mut self, time: &DateTime<Local>, environment: (f32, f32)) -> bool {
-        let (temp, humidity) = environment;
+        let (_, humidity) = environment;
         // Check if the mist should be on at the given time by:
-        // * Sorting the schedule by time
+        // * Resolving the schedule to times of day and sorting them
         // * Bucketing the schedule into pairs of on/off events
         // * Checking if the time is between any of the on/off pairs
-        let mist_on_schedule = self.mist.schedule.chunks(2).any(|pair| {
-            let on = &pair[0];
-            let off = &pair[1];
-
-            on.time.time() <= time.time() && off.time.time() > time.time()
-        });
+        let mist_on_schedule = self.scheduled(&self.mist.schedule, time);
 
         // Check if the mist should be on due to the humidity
         // If humidity is too high, we turn on to burn off the excess
@@ -346,17 +1154,27 @@ impl Config {
         !self.mist_on(time, environment)
     }
 
+    /// Check if the fan should be on at the given time by the schedule alone
+    pub fn fan_on_schedule(&mut self, time: &DateTime<Local>) -> bool {
+        self.scheduled(&self.fan.schedule, time)
+    }
+
     pub fn fan_on(&mut self, time: &DateTime<Local>, environment: (f32, f32)) -> bool {
+        self.fan_on_with_co2(time, environment, None)
+    }
+
+    pub fn fan_on_with_co2(
+        &mut self,
+        time: &DateTime<Local>,
+        environment: (f32, f32),
+        co2: Option<f32>,
+    ) -> bool {
         let (temp, humidity) = environment;
         // Check if the fan should be on at the given time by:
+        // * Resolving the schedule to times of day and sorting them
         // * Bucketing the schedule into pairs of on/off events
         // * Checking if the time is between any of the on/off pairs
-        let fan_on_schedule = self.fan.schedule.chunks(2).any(|pair| {
-            let on = &pair[0];
-            let off = &pair[1];
-
-            on.time.time() <= time.time() && off.time.time() > time.time()
-        });
+        let fan_on_schedule = self.scheduled(&self.fan.schedule, time);
 
         // Check if the fan should be on due to the humidity
         // If humidity is too high, we turn on to circulate and lower humidity
@@ -369,22 +1187,152 @@ impl Config {
         let fan_off_environment =
             humidity < self.thresholds.min_humidity || temp < self.thresholds.min_temp;
 
-        (fan_on_schedule || fan_on_environment) && !fan_off_environment
+        // If CO2 is too low, the plants have used it up and we force an air exchange
+        let fan_on_co2 = co2
+            .zip(self.thresholds.min_co2)
+            .is_some_and(|(co2, min_co2)| co2 < min_co2);
+
+        ((fan_on_schedule || fan_on_environment) && !fan_off_environment) || fan_on_co2
     }
 
     pub fn fan_off(&mut self, time: &DateTime<Local>, environment: (f32, f32)) -> bool {
         !self.fan_on(time, environment)
     }
 
+    pub fn pump(&self) -> Option<&PumpConfig> {
+        self.pump.as_ref()
+    }
+
+    /// Volumes of the scheduled pump doses due after `from`, up to and including `to`
+    pub fn pump_doses_due(&self, from: &DateTime<Local>, to: &DateTime<Local>) -> Vec<f64> {
+        self.pump.as_ref().map_or_else(Vec::new, |pump| {
+            pump.doses_due(from, to, self.location.as_ref())
+        })
+    }
+
+    pub fn auth(&self) -> Option<&AuthConfig> {
+        self.auth.as_ref()
+    }
+
+    pub fn mqtt(&self) -> Option<&MqttConfig> {
+        self.mqtt.as_ref()
+    }
+
+    pub fn history(&self) -> &HistoryConfig {
+        &self.history
+    }
+
+    pub fn thresholds(&self) -> &ThresholdConfig {
+        &self.thresholds
+    }
+
+    pub fn camera(&self) -> Option<&CameraConfig> {
+        self.camera.as_ref()
+    }
+
+    pub fn co2_sensor(&self) -> Option<&Co2SensorConfig> {
+        self.co2.as_ref()
+    }
+
+    pub fn soil(&self) -> Option<&SoilConfig> {
+        self.soil.as_ref()
+    }
+
+    pub fn door_switch(&self) -> Option<&SwitchConfig> {
+        self.door.as_ref().map(|door| &door.switch)
+    }
+
+    /// Whether the actuator should be turned off while the door is open
+    pub fn door_pauses(&self, actuator: Actuator) -> bool {
+        self.door
+            .as_ref()
+            .is_some_and(|door| door.pause.contains(&actuator))
+    }
+
+    pub fn door_settle(&self) -> Duration {
+        self.door
+            .as_ref()
+            .and_then(|door| door.settle)
+            .unwrap_or_else(Duration::zero)
+    }
+
+    pub fn mist_reservoir(&self) -> Option<&SwitchConfig> {
+        self.mist.reservoir.as_ref()
+    }
+
     pub fn fan_power(&self) -> FanPower {
         self.fan.power.clone()
     }
 
+    pub fn fan_tach_pins(&self) -> &[u8] {
+        &self.fan.tach_pins
+    }
+
+    /// Check if a fan driven at the given duty cycle is stalled at the given speed
+    pub fn fan_stalled(&self, duty_cycle: f64, rpm: f64) -> bool {
+        duty_cycle * FanPower::CONVERSION_FACTOR > self.fan.stall_threshold && rpm <= 0.0
+    }
+
     pub fn setup(&mut self) -> Result<()> {
-        // Sort the schedules by time ascending
-        self.light.schedule.sort_by(|a, b| a.time.cmp(&b.time));
-        self.mist.schedule.sort_by(|a, b| a.time.cmp(&b.time));
-        self.fan.schedule.sort_by(|a, b| a.time.cmp(&b.time));
+        if let Some(soil) = &self.soil {
+            soil.validate()?;
+        }
+
+        if let Some(pump) = &self.pump {
+            pump.validate()?;
+        }
+
+        if let Some(camera) = &self.camera {
+            camera.validate()?;
+        }
+
+        if let Some(mqtt) = &self.mqtt {
+            mqtt.validate()?;
+        }
+
+        self.history.validate()?;
+
+        // Expand any repeating windows into the schedules
+        for (schedule, repeat) in [
+            (&mut self.light.schedule, &mut self.light.repeat),
+            (&mut self.mist.schedule, &mut self.mist.repeat),
+            (&mut self.fan.schedule, &mut self.fan.repeat),
+        ] {
+            for r in repeat.drain(..) {
+                schedule.extend(r.expand()?);
+            }
+        }
+
+        // Solar events can only be resolved if we know where we are
+        let solar = self
+            .light
+            .schedule
+            .iter()
+            .chain(self.mist.schedule.iter())
+            .chain(self.fan.schedule.iter())
+            .any(|e| e.time.is_solar());
+
+        ensure!(
+            !solar || self.location.is_some(),
+            "Schedules using sunrise or sunset need a [location] with latitude and longitude"
+        );
+
+        ensure!(
+            self.light
+                .schedule
+                .iter()
+                .filter_map(|e| e.level)
+                .all(|level| (0.0..=FULL_LEVEL).contains(&level)),
+            "Light levels must be between 0 and 100.0 %"
+        );
+
+        // Sort the schedules by today's time ascending
+        let today = Local::now().date_naive();
+        let location = self.location.clone();
+        let key = |e: &Event| e.time.resolve(today, location.as_ref());
+        self.light.schedule.sort_by_key(key);
+        self.mist.schedule.sort_by_key(key);
+        self.fan.schedule.sort_by_key(key);
 
         // Ensure the schedule is valid (this reduces to the same as checking open/close parens lol)
         // We can start with either an on or off event, they just need to be balanced
@@ -438,21 +1386,127 @@ impl Config {
     }
 }
 
+/// Whether each sensor gave a good reading last time it was read, `None` for sensors that
+/// aren't configured
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct SensorHealth {
+    pub climate: bool,
+    pub co2: Option<bool>,
+    pub soil: Option<bool>,
+}
+
+/// The status the controller broadcasts each cycle
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct NetworkUpdate {
-    // fan_power: f64,
-    // light_on: bool,
-    temp: f32,
-    humidity: f32,
+    pub version: u32,
+    /// Counts up with each update, starting over when the controller restarts
+    pub seq: u64,
+    pub hostname: String,
+    /// Picked at random when the controller starts, which tells its restarts apart without
+    /// relying on a clock that may have gone back
+    pub boot_id: u64,
+    /// Seconds since the controller started
+    pub uptime: u64,
+    pub temp: Option<f32>,
+    pub humidity: Option<f32>,
+    pub co2: Option<f32>,
+    /// Soil moisture in percent for each pot
+    pub soil: HashMap<String, f32>,
+    /// Light level in percent
+    pub light_level: f64,
+    pub mist_on: bool,
+    /// Fan duty cycle from 0.0 to 1.0
+    pub fan_duty: f64,
+    pub fan_rpm: Vec<f64>,
+    pub thresholds: ThresholdConfig,
+    pub sensors: SensorHealth,
+    pub alarms: Vec<Alarm>,
+}
+
+/// Just enough of an update to tell which schema it was sent with, updates from before the
+/// schema was versioned are version 1
+#[derive(Deserialize)]
+struct UpdateVersion {
+    #[serde(default = "UpdateVersion::unversioned")]
+    version: u32,
+}
+
+impl UpdateVersion {
+    fn unversioned() -> u32 {
+        1
+    }
 }
 
 impl NetworkUpdate {
-    pub fn new(/* fan_power: f64, light_on: bool, */ temp: f32, humidity: f32) -> Self {
-        Self {
-            // fan_power,
-            // light_on,
-            temp,
-            humidity,
+    /// Version of the schema, bumped whenever a change would break older monitors
+    pub const VERSION: u32 = 3;
+
+    /// Parse an update, rejecting any sent with a different version of the schema
+    pub fn parse(s: &str) -> Result<Self> {
+        let UpdateVersion { version } = serde_json::from_str(s)?;
+
+        ensure!(
+            version == Self::VERSION,
+            "Update is version {}, expected version {}",
+            version,
+            Self::VERSION
+        );
+
+        Ok(serde_json::from_str(s)?)
+    }
+
+    pub fn alarms(&self) -> &[Alarm] {
+        &self.alarms
+    }
+}
+
+/// The last update seen from a controller, and the runs of it that have been restarted since
+#[derive(Debug, Clone, Default)]
+struct LastUpdate {
+    boot_id: u64,
+    seq: u64,
+    retired: HashSet<u64>,
+}
+
+/// Keeps track of the last update from each controller to weed out duplicate and out of order
+/// packets
+#[derive(Debug, Clone, Default)]
+pub struct UpdateTracker {
+    last: HashMap<String, LastUpdate>,
+}
+
+impl UpdateTracker {
+    /// Whether an update is newer than the last one from its controller, recording it if it
+    /// is. The sequence starts over when the controller restarts with a new boot ID.
+    pub fn accept(&mut self, update: &NetworkUpdate) -> bool {
+        let Some(last) = self.last.get_mut(&update.hostname) else {
+            self.last.insert(
+                update.hostname.clone(),
+                LastUpdate {
+                    boot_id: update.boot_id,
+                    seq: update.seq,
+                    retired: HashSet::new(),
+                },
+            );
+            return true;
+        };
+
+        if update.boot_id == last.boot_id {
+            if update.seq <= last.seq {
+                return false;
+            }
+        } else {
+            // Late packets from a run that has since been restarted
+            if last.retired.contains(&update.boot_id) {
+                return false;
+            }
+
+            last.retired.insert(last.boot_id);
         }
+
+        last.boot_id = update.boot_id;
+        last.seq = update.seq;
+
+        true
     }
 }
@@ -0,0 +1,205 @@
+use chrono::{DateTime, Local};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::{Display, Write},
+};
+
+use crate::{Actuator, NetworkUpdate};
+
+/// Counters and gauges the controller exposes for Prometheus to scrape
+#[derive(Debug, Clone, Default)]
+pub struct Metrics {
+    /// Latest raw temperature (F) and humidity reading
+    raw: Option<(f32, f32)>,
+    /// Failed reads for each sensor
+    read_failures: BTreeMap<&'static str, u64>,
+    /// Times each actuator was switched on or off
+    toggles: HashMap<Actuator, u64>,
+    /// Seconds each actuator was on for, not counting the time since it last came on
+    on_seconds: HashMap<Actuator, f64>,
+    /// When each actuator that is on came on
+    on_since: HashMap<Actuator, DateTime<Local>>,
+}
+
+impl Metrics {
+    /// Record a raw temperature (F) and humidity reading
+    pub fn reading(&mut self, temp: f32, humidity: f32) {
+        self.raw = Some((temp, humidity));
+    }
+
+    /// Record a failed read from a sensor
+    pub fn read_failed(&mut self, sensor: &'static str) {
+        *self.read_failures.entry(sensor).or_default() += 1;
+    }
+
+    /// Record an actuator being on or off at a time, counting it if it switched
+    pub fn switched(&mut self, actuator: Actuator, on: bool, time: DateTime<Local>) {
+        let was_on = self.on_since.contains_key(&actuator);
+
+        if on == was_on {
+            return;
+        }
+
+        *self.toggles.entry(actuator).or_default() += 1;
+
+        if on {
+            self.on_since.insert(actuator, time);
+        } else if let Some(since) = self.on_since.remove(&actuator) {
+            *self.on_seconds.entry(actuator).or_default() +=
+                (time - since).num_milliseconds() as f64 / 1000.0;
+        }
+    }
+
+    /// Whether an actuator is on as far as we know
+    pub fn is_on(&self, actuator: Actuator) -> bool {
+        self.on_since.contains_key(&actuator)
+    }
+
+    /// Seconds an actuator has been on for in total up to a time
+    pub fn on_seconds(&self, actuator: Actuator, now: DateTime<Local>) -> f64 {
+        let past = self.on_seconds.get(&actuator).copied().unwrap_or_default();
+        let current = self
+            .on_since
+            .get(&actuator)
+            .map(|since| (now - *since).num_milliseconds() as f64 / 1000.0)
+            .unwrap_or_default();
+
+        past + current
+    }
+
+    /// The metrics in the Prometheus text format, with the gauges the latest status update
+    /// carries
+    pub fn render(&self, latest: Option<&NetworkUpdate>, now: DateTime<Local>) -> String {
+        let mut out = String::new();
+
+        if let Some(update) = latest {
+            if let Some(temp) = update.temp {
+                gauge(
+                    &mut out,
+                    "grobot_temperature_fahrenheit",
+                    "Filtered temperature",
+                    temp,
+                );
+            }
+            if let Some(humidity) = update.humidity {
+                gauge(
+                    &mut out,
+                    "grobot_humidity_percent",
+                    "Filtered relative humidity",
+                    humidity,
+                );
+            }
+            if let Some(co2) = update.co2 {
+                gauge(&mut out, "grobot_co2_ppm", "Filtered CO2", co2);
+            }
+            gauge(
+                &mut out,
+                "grobot_light_level_percent",
+                "Light level",
+                update.light_level,
+            );
+            gauge(
+                &mut out,
+                "grobot_fan_duty_ratio",
+                "Fan duty cycle from 0 to 1",
+                update.fan_duty,
+            );
+        }
+
+        if let Some((temp, humidity)) = self.raw {
+            gauge(
+                &mut out,
+                "grobot_raw_temperature_fahrenheit",
+                "Latest raw temperature reading",
+                temp,
+            );
+            gauge(
+                &mut out,
+                "grobot_raw_humidity_percent",
+                "Latest raw relative humidity reading",
+                humidity,
+            );
+        }
+
+        header(
+            &mut out,
+            "grobot_actuator_on",
+            "Whether an actuator is on",
+            "gauge",
+        );
+        for actuator in Actuator::ALL {
+            sample(
+                &mut out,
+                "grobot_actuator_on",
+                "actuator",
+                actuator,
+                u8::from(self.is_on(actuator)),
+            );
+        }
+
+        header(
+            &mut out,
+            "grobot_actuator_toggles_total",
+            "Times an actuator was switched on or off",
+            "counter",
+        );
+        for actuator in Actuator::ALL {
+            let toggles = self.toggles.get(&actuator).copied().unwrap_or_default();
+            sample(
+                &mut out,
+                "grobot_actuator_toggles_total",
+                "actuator",
+                actuator,
+                toggles,
+            );
+        }
+
+        header(
+            &mut out,
+            "grobot_actuator_on_seconds_total",
+            "Seconds an actuator has been on for",
+            "counter",
+        );
+        for actuator in Actuator::ALL {
+            sample(
+                &mut out,
+                "grobot_actuator_on_seconds_total",
+                "actuator",
+                actuator,
+                self.on_seconds(actuator, now),
+            );
+        }
+
+        header(
+            &mut out,
+            "grobot_sensor_read_failures_total",
+            "Failed sensor reads",
+            "counter",
+        );
+        for (sensor, failures) in &self.read_failures {
+            sample(
+                &mut out,
+                "grobot_sensor_read_failures_total",
+                "sensor",
+                sensor,
+                failures,
+            );
+        }
+
+        out
+    }
+}
+
+fn header(out: &mut String, name: &str, help: &str, kind: &str) {
+    // Writing to a String can't fail
+    let _ = writeln!(out, "# HELP {} {}\n# TYPE {} {}", name, help, name, kind);
+}
+
+fn gauge(out: &mut String, name: &str, help: &str, value: impl Display) {
+    header(out, name, help, "gauge");
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+fn sample(out: &mut String, name: &str, label: &str, value: impl Display, sample: impl Display) {
+    let _ = writeln!(out, "{}{{{}=\"{}\"}} {}", name, label, value, sample);
+}
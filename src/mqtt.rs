@@ -0,0 +1,354 @@
+use anyhow::{bail, ensure, Result};
+use rumqttc::{LastWill, MqttOptions, QoS};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::time::Duration;
+
+use crate::{Actuator, Command, Config, StreamEvent};
+
+fn default_mqtt_port() -> u16 {
+    MqttConfig::DEFAULT_PORT
+}
+
+fn default_prefix() -> String {
+    "grobot".to_string()
+}
+
+fn default_discovery_prefix() -> String {
+    "homeassistant".to_string()
+}
+
+/// A broker to publish readings and actuator states to, and take overrides from
+#[derive(Deserialize, Debug, Clone)]
+pub struct MqttConfig {
+    pub host: String,
+    #[serde(default = "default_mqtt_port")]
+    pub port: u16,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// Topics are published under `<prefix>/<hostname>`
+    #[serde(default = "default_prefix")]
+    pub prefix: String,
+    /// Prefix Home Assistant looks for discovery payloads under, or none sent if empty
+    #[serde(default = "default_discovery_prefix")]
+    pub discovery_prefix: String,
+}
+
+impl MqttConfig {
+    const DEFAULT_PORT: u16 = 1883;
+    const KEEP_ALIVE: Duration = Duration::from_secs(30);
+
+    pub fn validate(&self) -> Result<()> {
+        ensure!(!self.host.is_empty(), "MQTT host must be given");
+        ensure!(
+            self.username.is_some() == self.password.is_some(),
+            "MQTT username and password must be given together"
+        );
+
+        for prefix in [&self.prefix, &self.discovery_prefix] {
+            ensure!(
+                !prefix.contains(['+', '#']),
+                "MQTT prefix '{}' can't contain wildcards",
+                prefix
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Options to connect with, leaving the controller marked offline if it drops off
+    pub fn options(&self, topics: &Topics) -> MqttOptions {
+        let mut options = MqttOptions::new(&topics.node, &self.host, self.port);
+        options.set_keep_alive(Self::KEEP_ALIVE);
+        options.set_last_will(LastWill::new(
+            topics.availability(),
+            Topics::OFFLINE,
+            QoS::AtLeastOnce,
+            true,
+        ));
+
+        if let (Some(username), Some(password)) = (&self.username, &self.password) {
+            options.set_credentials(username, password);
+        }
+
+        options
+    }
+}
+
+/// A topic name or ID made of letters, digits and underscores
+fn slug(name: &str) -> String {
+    name.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_lowercase()
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+fn on_off(on: bool) -> String {
+    if on { "ON" } else { "OFF" }.to_string()
+}
+
+/// The topics a controller publishes to and listens on
+#[derive(Debug, Clone)]
+pub struct Topics {
+    /// The controller's hostname
+    hostname: String,
+    /// The hostname as an ID, used as the client ID and in unique IDs
+    node: String,
+    /// Prefix of every state and command topic
+    base: String,
+    discovery_prefix: Option<String>,
+}
+
+impl Topics {
+    pub const ONLINE: &'static str = "online";
+    pub const OFFLINE: &'static str = "offline";
+
+    pub fn new(config: &MqttConfig, hostname: &str) -> Self {
+        let node = slug(hostname);
+
+        Self {
+            hostname: hostname.to_string(),
+            base: format!("{}/{}", config.prefix, node),
+            node,
+            discovery_prefix: Some(config.discovery_prefix.clone())
+                .filter(|prefix| !prefix.is_empty()),
+        }
+    }
+
+    /// Where `online` is published on connecting, and `offline` by the broker on a drop out
+    pub fn availability(&self) -> String {
+        format!("{}/availability", self.base)
+    }
+
+    /// Where a reading or state is published
+    pub fn state(&self, name: &str) -> String {
+        format!("{}/{}", self.base, name)
+    }
+
+    /// Where an actuator's on/off state is published
+    pub fn actuator_state(&self, actuator: Actuator) -> String {
+        format!("{}/{}/state", self.base, actuator)
+    }
+
+    /// Where commands for an actuator are taken from: `ON`, `OFF` or `AUTO`
+    pub fn command(&self, actuator: Actuator) -> String {
+        format!("{}/{}/set", self.base, actuator)
+    }
+
+    /// Filter matching every command topic
+    pub fn commands(&self) -> String {
+        format!("{}/+/set", self.base)
+    }
+
+    /// The command a message on a command topic asks for
+    pub fn parse_command(&self, topic: &str, payload: &[u8]) -> Result<Command> {
+        let Some(actuator) = topic
+            .strip_prefix(&format!("{}/", self.base))
+            .and_then(|topic| topic.strip_suffix("/set"))
+        else {
+            bail!("Not a command topic: {}", topic);
+        };
+
+        let actuator = actuator.parse()?;
+        let payload = String::from_utf8_lossy(payload);
+
+        Ok(match payload.trim().to_ascii_uppercase().as_str() {
+            "ON" => Command::Override {
+                actuator,
+                on: true,
+                seconds: None,
+            },
+            "OFF" => Command::Override {
+                actuator,
+                on: false,
+                seconds: None,
+            },
+            "AUTO" => Command::Auto { actuator },
+            _ => bail!("Unknown command '{}', expected ON, OFF or AUTO", payload),
+        })
+    }
+
+    /// The topics and payloads to publish for an event
+    pub fn publications(&self, event: &StreamEvent) -> Vec<(String, String)> {
+        let mut out = Vec::new();
+
+        match event {
+            StreamEvent::Status(update) => {
+                if let Some(temp) = update.temp {
+                    out.push((self.state("temperature"), temp.to_string()));
+                }
+                if let Some(humidity) = update.humidity {
+                    out.push((self.state("humidity"), humidity.to_string()));
+                }
+                if let Some(co2) = update.co2 {
+                    out.push((self.state("co2"), co2.to_string()));
+                }
+                for (pot, moisture) in &update.soil {
+                    out.push((
+                        self.state(&format!("soil/{}", slug(pot))),
+                        moisture.to_string(),
+                    ));
+                }
+                out.push((self.state("light_level"), update.light_level.to_string()));
+                out.push((
+                    self.actuator_state(Actuator::Light),
+                    on_off(update.light_level > 0.0),
+                ));
+                out.push((self.actuator_state(Actuator::Mist), on_off(update.mist_on)));
+                out.push((
+                    self.actuator_state(Actuator::Fan),
+                    on_off(update.fan_duty > 0.0),
+                ));
+                out.push((self.state("problem"), on_off(!update.alarms.is_empty())));
+            }
+            StreamEvent::Light { level } => {
+                out.push((self.state("light_level"), level.to_string()));
+                out.push((self.actuator_state(Actuator::Light), on_off(*level > 0.0)));
+            }
+            StreamEvent::Actuator { actuator, on } => {
+                out.push((self.actuator_state(*actuator), on_off(*on)));
+            }
+            _ => {}
+        }
+
+        out
+    }
+
+    /// Home Assistant discovery topics and payloads for the sensors and switches a config has
+    pub fn discovery(&self, config: &Config) -> Vec<(String, String)> {
+        let Some(prefix) = &self.discovery_prefix else {
+            return Vec::new();
+        };
+
+        let mut entities = vec![
+            (
+                "sensor",
+                "temperature".to_string(),
+                json!({
+                    "name": "Temperature",
+                    "state_topic": self.state("temperature"),
+                    "unit_of_measurement": "°F",
+                    "device_class": "temperature",
+                    "state_class": "measurement",
+                }),
+            ),
+            (
+                "sensor",
+                "humidity".to_string(),
+                json!({
+                    "name": "Humidity",
+                    "state_topic": self.state("humidity"),
+                    "unit_of_measurement": "%",
+                    "device_class": "humidity",
+                    "state_class": "measurement",
+                }),
+            ),
+            (
+                "sensor",
+                "light_level".to_string(),
+                json!({
+                    "name": "Light level",
+                    "state_topic": self.state("light_level"),
+                    "unit_of_measurement": "%",
+                    "state_class": "measurement",
+                }),
+            ),
+            (
+                "binary_sensor",
+                "problem".to_string(),
+                json!({
+                    "name": "Problem",
+                    "state_topic": self.state("problem"),
+                    "device_class": "problem",
+                }),
+            ),
+        ];
+
+        if config.co2_sensor().is_some() {
+            entities.push((
+                "sensor",
+                "co2".to_string(),
+                json!({
+                    "name": "CO2",
+                    "state_topic": self.state("co2"),
+                    "unit_of_measurement": "ppm",
+                    "device_class": "carbon_dioxide",
+                    "state_class": "measurement",
+                }),
+            ));
+        }
+
+        for sensor in config.soil().map(|soil| &soil.sensors[..]).unwrap_or(&[]) {
+            let pot = slug(&sensor.name);
+
+            entities.push((
+                "sensor",
+                format!("soil_{}", pot),
+                json!({
+                    "name": format!("{} moisture", sensor.name),
+                    "state_topic": self.state(&format!("soil/{}", pot)),
+                    "unit_of_measurement": "%",
+                    "device_class": "moisture",
+                    "state_class": "measurement",
+                }),
+            ));
+        }
+
+        for actuator in Actuator::ALL {
+            let name = actuator.to_string();
+            let title = format!("{}{}", name[..1].to_uppercase(), &name[1..]);
+
+            entities.push((
+                "switch",
+                name.clone(),
+                json!({
+                    "name": title,
+                    "state_topic": self.actuator_state(actuator),
+                    "command_topic": self.command(actuator),
+                    "payload_on": "ON",
+                    "payload_off": "OFF",
+                }),
+            ));
+            entities.push((
+                "button",
+                format!("{}_auto", name),
+                json!({
+                    "name": format!("{} automatic", title),
+                    "command_topic": self.command(actuator),
+                    "payload_press": "AUTO",
+                }),
+            ));
+        }
+
+        entities
+            .into_iter()
+            .map(|(component, object, mut payload)| {
+                if let Value::Object(fields) = &mut payload {
+                    fields.insert(
+                        "unique_id".to_string(),
+                        json!(format!("{}_{}", self.node, object)),
+                    );
+                    fields.insert("availability_topic".to_string(), json!(self.availability()));
+                    fields.insert(
+                        "device".to_string(),
+                        json!({
+                            "identifiers": [self.node],
+                            "name": self.hostname,
+                            "manufacturer": "grobot",
+                        }),
+                    );
+                }
+
+                (
+                    format!("{}/{}/{}/{}/config", prefix, component, self.node, object),
+                    payload.to_string(),
+                )
+            })
+            .collect()
+    }
+}
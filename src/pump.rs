@@ -0,0 +1,165 @@
+use anyhow::{ensure, Result};
+use chrono::{DateTime, Duration, Local, NaiveDate, NaiveTime};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use crate::{deserialize_duration, Event, Location, TimeSpec};
+
+fn default_cooldown() -> Duration {
+    Duration::minutes(30)
+}
+
+/// A scheduled watering
+#[derive(Deserialize, Debug, Clone)]
+pub struct Dose {
+    #[serde(deserialize_with = "Event::parse_time")]
+    time: TimeSpec,
+    /// Volume to pump in milliliters
+    volume: f64,
+}
+
+/// Water a pot when its soil moisture drops too low
+#[derive(Deserialize, Debug, Clone)]
+pub struct MoistureRule {
+    /// Name of the soil sensor in the pot
+    pot: String,
+    /// Moisture in percent below which the pot is watered
+    below: f32,
+    /// Volume to pump in milliliters
+    volume: f64,
+    /// Minimum time between waterings, so the water has time to soak in and the moisture
+    /// readings, which are averaged, have time to catch up
+    #[serde(
+        default = "default_cooldown",
+        deserialize_with = "deserialize_duration"
+    )]
+    cooldown: Duration,
+}
+
+/// An irrigation pump on a relay channel
+#[derive(Deserialize, Debug, Clone)]
+pub struct PumpConfig {
+    /// Pin for the relay channel the pump is on
+    pub pin: u8,
+    /// Calibrated flow rate of the pump in milliliters per second
+    pub flow_rate: f64,
+    /// Most the pump will dispense in a day, in milliliters
+    pub max_daily: Option<f64>,
+    #[serde(default)]
+    schedule: Vec<Dose>,
+    #[serde(default)]
+    rules: Vec<MoistureRule>,
+}
+
+impl PumpConfig {
+    pub fn validate(&self) -> Result<()> {
+        ensure!(self.flow_rate > 0.0, "Pump flow rate must be above zero");
+        ensure!(
+            self.schedule
+                .iter()
+                .map(|d| d.volume)
+                .chain(self.rules.iter().map(|r| r.volume))
+                .all(|volume| volume > 0.0),
+            "Pump doses must be above zero"
+        );
+        ensure!(
+            self.rules.iter().all(|r| r.cooldown > Duration::zero()),
+            "Pump rule cooldowns must be above zero"
+        );
+
+        Ok(())
+    }
+
+    /// How long the pump needs to run to dispense a volume
+    pub fn run_time(&self, volume: f64) -> std::time::Duration {
+        std::time::Duration::from_secs_f64(volume / self.flow_rate)
+    }
+
+    /// Times of the scheduled doses on a date
+    pub fn dose_times(&self, date: NaiveDate, location: Option<&Location>) -> Vec<NaiveTime> {
+        self.schedule
+            .iter()
+            .filter_map(|dose| dose.time.resolve(date, location))
+            .collect()
+    }
+
+    /// Volumes of the scheduled doses due after `from`, up to and including `to`
+    pub fn doses_due(
+        &self,
+        from: &DateTime<Local>,
+        to: &DateTime<Local>,
+        location: Option<&Location>,
+    ) -> Vec<f64> {
+        let date = to.date_naive();
+        // If the day rolled over, everything since midnight is due
+        let from = if from.date_naive() == date {
+            Some(from.time())
+        } else {
+            None
+        };
+
+        self.schedule
+            .iter()
+            .filter(|dose| {
+                dose.time
+                    .resolve(date, location)
+                    .is_some_and(|time| from.is_none_or(|from| from < time) && time <= to.time())
+            })
+            .map(|dose| dose.volume)
+            .collect()
+    }
+
+    /// Pots that are too dry and aren't cooling down from a recent watering, with the volume
+    /// to water them with
+    pub fn rule_doses(
+        &self,
+        moisture: &HashMap<String, f32>,
+        last_watered: &HashMap<String, DateTime<Local>>,
+        now: &DateTime<Local>,
+    ) -> Vec<(String, f64)> {
+        self.rules
+            .iter()
+            .filter(|rule| {
+                moisture
+                    .get(&rule.pot)
+                    .is_some_and(|moisture| *moisture < rule.below)
+            })
+            .filter(|rule| {
+                last_watered
+                    .get(&rule.pot)
+                    .is_none_or(|last| *now - *last >= rule.cooldown)
+            })
+            .map(|rule| (rule.pot.clone(), rule.volume))
+            .collect()
+    }
+}
+
+/// Keeps track of how much the pump has dispensed today
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct PumpLedger {
+    date: Option<NaiveDate>,
+    dispensed: f64,
+}
+
+impl PumpLedger {
+    /// Record a dose, returning how much of it fits under the daily maximum
+    pub fn dispense(&mut self, date: NaiveDate, volume: f64, max_daily: Option<f64>) -> f64 {
+        if self.date != Some(date) {
+            self.date = Some(date);
+            self.dispensed = 0.0;
+        }
+
+        let volume = match max_daily {
+            Some(max_daily) => volume.min(max_daily - self.dispensed).max(0.0),
+            None => volume,
+        };
+
+        self.dispensed += volume;
+        volume
+    }
+
+    /// How much has been dispensed today, in milliliters
+    pub fn dispensed(&self) -> f64 {
+        self.dispensed
+    }
+}
@@ -0,0 +1,166 @@
+use anyhow::{ensure, Result};
+use ringbuffer::{AllocRingBuffer, RingBuffer, RingBufferExt, RingBufferWrite};
+use rppal::i2c::I2c;
+use serde::Deserialize;
+use std::{collections::HashMap, thread::sleep, time::Duration};
+use tracing::{info, warn};
+
+fn default_adc_address() -> u16 {
+    Ads1115::DEFAULT_ADDRESS
+}
+
+/// A capacitive soil moisture sensor on one channel of the ADC
+#[derive(Deserialize, Debug, Clone)]
+pub struct SoilSensorConfig {
+    /// Name of the pot the sensor is in
+    pub name: String,
+    /// ADC channel, 0-3
+    pub channel: u8,
+    /// Raw reading with the sensor in dry air
+    pub dry: i16,
+    /// Raw reading with the sensor in water
+    pub wet: i16,
+}
+
+impl SoilSensorConfig {
+    /// Convert a raw reading to moisture in percent using the calibration
+    pub fn moisture(&self, raw: i16) -> f32 {
+        let moisture = (self.dry as f32 - raw as f32) / (self.dry as f32 - self.wet as f32);
+        (moisture * 100.0).clamp(0.0, 100.0)
+    }
+}
+
+/// Soil moisture sensors read through an ADS1115 ADC on the I2C bus
+#[derive(Deserialize, Debug, Clone)]
+pub struct SoilConfig {
+    /// I2C address of the ADC, 0x48 unless the ADDR pin is tied elsewhere
+    #[serde(default = "default_adc_address")]
+    pub address: u16,
+    pub sensors: Vec<SoilSensorConfig>,
+}
+
+impl SoilConfig {
+    pub fn validate(&self) -> Result<()> {
+        for sensor in &self.sensors {
+            ensure!(
+                sensor.channel < Ads1115::CHANNELS,
+                "Soil sensor '{}' must be on ADC channel 0-3",
+                sensor.name
+            );
+            ensure!(
+                sensor.dry != sensor.wet,
+                "Soil sensor '{}' dry and wet calibration must differ",
+                sensor.name
+            );
+        }
+
+        Ok(())
+    }
+}
+
+pub struct Ads1115(I2c);
+
+impl Ads1115 {
+    const DEFAULT_ADDRESS: u16 = 0x48;
+    const CHANNELS: u8 = 4;
+    const CONVERSION_REGISTER: u8 = 0x00;
+    const CONFIG_REGISTER: u8 = 0x01;
+    /// Start a single conversion
+    const START: u16 = 0x8000;
+    /// Measure a channel against ground, OR'd with the channel
+    const SINGLE_ENDED: u16 = 0x4;
+    /// +/-4.096V full scale, enough for sensors powered from 3.3V
+    const GAIN_4V: u16 = 0x0200;
+    /// Power down between single conversions
+    const SINGLE_SHOT: u16 = 0x0100;
+    /// 128 samples per second
+    const RATE_128: u16 = 0x0080;
+    /// Turn off the comparator
+    const NO_COMPARATOR: u16 = 0x0003;
+    /// One conversion at 128 samples per second takes just under 8ms
+    const CONVERSION_TIME: Duration = Duration::from_millis(10);
+
+    pub fn new(address: u16) -> Result<Self> {
+        let mut i2c = I2c::new()?;
+        i2c.set_slave_address(address)?;
+        Ok(Self(i2c))
+    }
+
+    /// Read the raw value of a channel
+    pub fn read(&mut self, channel: u8) -> Result<i16> {
+        let config = Self::START
+            | ((Self::SINGLE_ENDED | channel as u16) << 12)
+            | Self::GAIN_4V
+            | Self::SINGLE_SHOT
+            | Self::RATE_128
+            | Self::NO_COMPARATOR;
+
+        self.0
+            .block_write(Self::CONFIG_REGISTER, &config.to_be_bytes())?;
+
+        sleep(Self::CONVERSION_TIME);
+
+        let mut buf = [0u8; 2];
+        self.0.block_read(Self::CONVERSION_REGISTER, &mut buf)?;
+
+        Ok(i16::from_be_bytes(buf))
+    }
+}
+
+/// History of soil moisture readings for each sensor
+pub struct Soil {
+    config: SoilConfig,
+    readings: Vec<AllocRingBuffer<f32>>,
+}
+
+impl Soil {
+    const DEFAULT_INITIAL_READINGS: usize = 8;
+
+    pub fn new(config: SoilConfig) -> Self {
+        let readings = config
+            .sensors
+            .iter()
+            .map(|_| AllocRingBuffer::with_capacity(Self::DEFAULT_INITIAL_READINGS))
+            .collect();
+
+        Self { config, readings }
+    }
+
+    /// Do a single reading from each sensor, returning whether they all read
+    pub fn read(&mut self, adc: &mut Ads1115) -> bool {
+        let mut ok = true;
+
+        for (sensor, readings) in self.config.sensors.iter().zip(self.readings.iter_mut()) {
+            match adc.read(sensor.channel) {
+                Ok(raw) => {
+                    let moisture = sensor.moisture(raw);
+                    info!(
+                        "Added new soil reading for {}: {} ({}%)",
+                        sensor.name, raw, moisture
+                    );
+                    readings.push(moisture);
+                }
+                Err(e) => {
+                    warn!("Failed to read soil sensor {}: {}", sensor.name, e);
+                    ok = false;
+                }
+            }
+        }
+
+        ok
+    }
+
+    /// The average moisture of each pot in percent, for sensors with readings
+    pub fn moisture(&self) -> HashMap<String, f32> {
+        self.config
+            .sensors
+            .iter()
+            .zip(self.readings.iter())
+            .filter(|(_, readings)| !readings.is_empty())
+            .map(|(sensor, readings)| {
+                let mean = readings.iter().sum::<f32>() / readings.len() as f32;
+                (sensor.name.clone(), mean)
+            })
+            .collect()
+    }
+}
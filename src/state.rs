@@ -0,0 +1,77 @@
+use anyhow::Result;
+use chrono::{DateTime, Duration, Local};
+use serde::{Deserialize, Serialize};
+use serde_json::{from_str, to_string};
+use std::{collections::HashMap, path::Path};
+use tokio::fs::{create_dir_all, read_to_string, rename, write};
+
+use crate::{DliTracker, PumpLedger};
+
+/// What the controller knows that would otherwise be lost on a restart
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct State {
+    pub saved_at: Option<DateTime<Local>>,
+    /// Raw temperature (C) and humidity readings, oldest first
+    #[serde(default)]
+    pub readings: Vec<(f32, f32)>,
+    /// CO2 readings in ppm, oldest first
+    #[serde(default)]
+    pub co2: Vec<f32>,
+    /// Light level in percent
+    #[serde(default)]
+    pub light_level: f64,
+    #[serde(default)]
+    pub mist_on: bool,
+    #[serde(default)]
+    pub fan_on: bool,
+    #[serde(default)]
+    pub dli: DliTracker,
+    #[serde(default)]
+    pub pump: PumpLedger,
+    /// When each pot was last watered by a moisture rule
+    #[serde(default)]
+    pub last_watered: HashMap<String, DateTime<Local>>,
+}
+
+impl State {
+    /// Minutes after which readings and actuator states are too stale to pick up from
+    const MAX_AGE_MINUTES: i64 = 15;
+
+    pub async fn load(path: &Path) -> Result<Self> {
+        Ok(from_str(&read_to_string(path).await?)?)
+    }
+
+    /// Save to a path, going through a temporary file so a crash never leaves half a file
+    pub async fn save(&self, path: &Path) -> Result<()> {
+        if let Some(dir) = path.parent() {
+            create_dir_all(dir).await?;
+        }
+
+        let tmp = path.with_extension("tmp");
+        write(&tmp, to_string(self)?).await?;
+        rename(&tmp, path).await?;
+
+        Ok(())
+    }
+
+    /// Whether the readings and actuator states were saved recently enough to pick up from
+    pub fn is_fresh(&self, now: &DateTime<Local>) -> bool {
+        self.saved_at
+            .is_some_and(|saved_at| *now - saved_at <= Duration::minutes(Self::MAX_AGE_MINUTES))
+    }
+
+    /// Drop the readings and actuator states if they are too stale to pick up from, keeping
+    /// the daily counters which start over on their own
+    pub fn without_stale(self, now: &DateTime<Local>) -> Self {
+        if self.is_fresh(now) {
+            return self;
+        }
+
+        Self {
+            dli: self.dli,
+            pump: self.pump,
+            last_watered: self.last_watered,
+            ..Self::default()
+        }
+    }
+}
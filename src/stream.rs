@@ -0,0 +1,74 @@
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use crate::{Actuator, Alarm, NetworkUpdate, Override};
+
+/// Something that happened in the controller, streamed to anyone listening as it happens
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum StreamEvent {
+    /// A temperature (F) and humidity reading
+    Environment {
+        temp: f32,
+        humidity: f32,
+    },
+    /// A CO2 reading in ppm
+    Co2 {
+        ppm: f32,
+    },
+    /// Soil moisture in percent for each pot
+    Soil {
+        moisture: HashMap<String, f32>,
+    },
+    /// The light level changed, in percent
+    Light {
+        level: f64,
+    },
+    /// An on/off actuator was switched
+    Actuator {
+        actuator: Actuator,
+        on: bool,
+    },
+    /// An actuator was overridden, or handed back to automatic if `held` is `None`
+    Override {
+        actuator: Actuator,
+        held: Option<Override>,
+    },
+    /// Fan speeds in RPM
+    FanSpeed {
+        rpm: Vec<f64>,
+    },
+    Alarm {
+        alarm: Alarm,
+    },
+    AlarmCleared {
+        alarm: Alarm,
+    },
+    DoorOpened,
+    DoorClosed {
+        time: DateTime<Local>,
+    },
+    /// The status update sent out this cycle
+    Status(Box<NetworkUpdate>),
+}
+
+impl StreamEvent {
+    /// Name of the event, as given in its `event` field
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Environment { .. } => "environment",
+            Self::Co2 { .. } => "co2",
+            Self::Soil { .. } => "soil",
+            Self::Light { .. } => "light",
+            Self::Actuator { .. } => "actuator",
+            Self::Override { .. } => "override",
+            Self::FanSpeed { .. } => "fan_speed",
+            Self::Alarm { .. } => "alarm",
+            Self::AlarmCleared { .. } => "alarm_cleared",
+            Self::DoorOpened => "door_opened",
+            Self::DoorClosed { .. } => "door_closed",
+            Self::Status(_) => "status",
+        }
+    }
+}
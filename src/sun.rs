@@ -0,0 +1,69 @@
+use chrono::{DateTime, NaiveDate, TimeZone, Utc};
+use serde::Deserialize;
+
+/// Days from the unix epoch to the J2000 epoch (2000-01-01 12:00 UTC)
+const J2000_UNIX_DAYS: f64 = 10957.5;
+/// Axial tilt of the earth, in degrees
+const OBLIQUITY: f64 = 23.4397;
+/// Solar altitude at sunrise/sunset, accounting for refraction and the size of the disc
+const HORIZON: f64 = -0.833;
+
+/// Where the cabinet is, used to compute sunrise and sunset times offline
+#[derive(Deserialize, Debug, Clone)]
+pub struct Location {
+    /// Degrees north of the equator (south is negative)
+    pub latitude: f64,
+    /// Degrees east of Greenwich (west is negative)
+    pub longitude: f64,
+}
+
+impl Location {
+    /// Compute the sunrise and sunset for the given date using the sunrise equation. Returns
+    /// `None` if the sun does not rise or set on that day (polar day or night).
+    ///
+    /// This is accurate to within a minute or two, which is plenty for a light schedule.
+    pub fn sunrise_sunset(&self, date: NaiveDate) -> Option<(DateTime<Utc>, DateTime<Utc>)> {
+        let epoch = NaiveDate::from_ymd_opt(2000, 1, 1)?;
+        let n = (date - epoch).num_days() as f64;
+
+        // Mean solar noon, in days since J2000
+        let mean_noon = n + 0.0008 - self.longitude / 360.0;
+        // Solar mean anomaly
+        let m = (357.5291 + 0.985_600_28 * mean_noon).rem_euclid(360.0);
+        // Equation of the center
+        let c = 1.9148 * sin(m) + 0.02 * sin(2.0 * m) + 0.0003 * sin(3.0 * m);
+        // Ecliptic longitude
+        let lambda = (m + c + 180.0 + 102.9372).rem_euclid(360.0);
+        // Solar transit
+        let transit = mean_noon + 0.0053 * sin(m) - 0.0069 * sin(2.0 * lambda);
+        // Declination of the sun
+        let declination = (sin(lambda) * sin(OBLIQUITY)).asin().to_degrees();
+        // Hour angle
+        let cos_hour_angle = (sin(HORIZON) - sin(self.latitude) * sin(declination))
+            / (cos(self.latitude) * cos(declination));
+
+        if !(-1.0..=1.0).contains(&cos_hour_angle) {
+            return None;
+        }
+
+        let hour_angle = cos_hour_angle.acos().to_degrees();
+
+        Some((
+            from_j2000_days(transit - hour_angle / 360.0)?,
+            from_j2000_days(transit + hour_angle / 360.0)?,
+        ))
+    }
+}
+
+fn sin(degrees: f64) -> f64 {
+    degrees.to_radians().sin()
+}
+
+fn cos(degrees: f64) -> f64 {
+    degrees.to_radians().cos()
+}
+
+fn from_j2000_days(days: f64) -> Option<DateTime<Utc>> {
+    let seconds = ((days + J2000_UNIX_DAYS) * 86400.0).round() as i64;
+    Utc.timestamp_opt(seconds, 0).single()
+}
@@ -0,0 +1,120 @@
+use anyhow::Result;
+use axum::http::{header::AUTHORIZATION, HeaderMap, HeaderValue};
+use chrono::{Duration, Local, NaiveDate};
+use grobot::{api::DASHBOARD, Api, Config, NetworkUpdate, SensorHealth};
+use std::collections::HashMap;
+use tokio::sync::mpsc;
+use toml::from_str;
+
+const CONFIG: &str = include_str!("../configs/default.toml");
+
+fn api(source: &str) -> Result<Api> {
+    let mut config: Config = from_str(source)?;
+    config.setup()?;
+    let (commands, _) = mpsc::channel(1);
+
+    Ok(Api::new(config, source, commands))
+}
+
+fn update(config: &Config, seq: u64) -> NetworkUpdate {
+    NetworkUpdate {
+        version: NetworkUpdate::VERSION,
+        seq,
+        hostname: "grobot".to_string(),
+        boot_id: 1,
+        uptime: 0,
+        temp: Some(72.0),
+        humidity: Some(60.0),
+        co2: None,
+        soil: HashMap::new(),
+        light_level: 0.0,
+        mist_on: false,
+        fan_duty: 0.0,
+        fan_rpm: Vec::new(),
+        thresholds: config.thresholds().clone(),
+        sensors: SensorHealth::default(),
+        alarms: Vec::new(),
+    }
+}
+
+#[test]
+fn test_api_history() -> Result<()> {
+    let config: Config = from_str(CONFIG)?;
+    let mut api = api(CONFIG)?;
+    assert!(api.latest().is_none());
+
+    let now = Local::now();
+    api.record(now - Duration::hours(2), update(&config, 1));
+    api.record(now - Duration::minutes(5), update(&config, 2));
+    api.record(now, update(&config, 3));
+
+    assert_eq!(api.latest().map(|update| update.seq), Some(3));
+    assert_eq!(api.history(None).len(), 3);
+
+    let recent = api.history(Some(&(now - Duration::hours(1))));
+    assert_eq!(
+        recent
+            .iter()
+            .map(|entry| entry.update.seq)
+            .collect::<Vec<_>>(),
+        vec![2, 3]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_api_config() -> Result<()> {
+    let source = format!(
+        "{}\n[auth]\nkey = \"secret\"\n\n[mqtt]\nhost = \"broker\"\nusername = \"grobot\"\npassword = \"hunter2\"\n",
+        CONFIG
+    );
+    let api = api(&source)?;
+
+    assert!(api.source().get("thresholds").is_some());
+    assert!(
+        api.source().get("auth").is_none(),
+        "the key is never served"
+    );
+
+    let mqtt = &api.source()["mqtt"];
+    assert_eq!(mqtt["host"], "broker");
+    assert!(
+        mqtt.get("username").is_none() && mqtt.get("password").is_none(),
+        "broker credentials are never served"
+    );
+
+    let date = NaiveDate::from_ymd_opt(2023, 4, 23).unwrap();
+    let schedule = api.schedule(date);
+    assert!(!schedule["light"].is_empty());
+    assert!(schedule.contains_key("mist"));
+    assert!(schedule.contains_key("fan"));
+
+    Ok(())
+}
+
+#[test]
+fn test_api_authorized() -> Result<()> {
+    let mut headers = HeaderMap::new();
+    headers.insert(AUTHORIZATION, HeaderValue::from_static("Bearer secret"));
+
+    assert!(!api(CONFIG)?.authorized(&headers), "no key configured");
+
+    let api = api(&format!("{}\n[auth]\nkey = \"secret\"\n", CONFIG))?;
+    assert!(api.authorized(&headers));
+    assert!(!api.authorized(&HeaderMap::new()));
+
+    headers.insert(AUTHORIZATION, HeaderValue::from_static("Bearer secrets"));
+    assert!(!api.authorized(&headers));
+
+    Ok(())
+}
+
+#[test]
+fn test_dashboard_self_contained() {
+    assert!(DASHBOARD.contains("/api/status"));
+    assert!(
+        !DASHBOARD.contains("http://") && !DASHBOARD.contains("https://"),
+        "the dashboard loads nothing from outside the controller"
+    );
+}
@@ -0,0 +1,57 @@
+use anyhow::Result;
+use grobot::{Authenticator, Envelope};
+use serde_json::{from_str, to_string};
+
+const KEY: &str = "correct horse battery staple";
+const NOW: i64 = 1_682_236_800;
+
+#[test]
+fn test_seal_and_open() -> Result<()> {
+    let sender = Authenticator::new(KEY);
+    let mut receiver = Authenticator::new(KEY);
+
+    let packet = sender.seal_at(r#"{"command":"Status"}"#, NOW, 1)?;
+    assert_eq!(
+        receiver.open_at(&packet, NOW + 2)?,
+        r#"{"command":"Status"}"#
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_reject_replay() -> Result<()> {
+    let sender = Authenticator::new(KEY);
+    let mut receiver = Authenticator::new(KEY);
+
+    let packet = sender.seal_at("{}", NOW, 1)?;
+    receiver.open_at(&packet, NOW)?;
+    assert!(receiver.open_at(&packet, NOW + 1).is_err(), "same nonce");
+
+    let packet = sender.seal_at("{}", NOW - 60, 2)?;
+    assert!(receiver.open_at(&packet, NOW).is_err(), "too old");
+
+    Ok(())
+}
+
+#[test]
+fn test_reject_forgery() -> Result<()> {
+    let mut receiver = Authenticator::new(KEY);
+
+    let packet = Authenticator::new("wrong key").seal_at("{}", NOW, 1)?;
+    assert!(receiver.open_at(&packet, NOW).is_err(), "wrong key");
+
+    let mut envelope: Envelope = from_str(&Authenticator::new(KEY).seal_at("{}", NOW, 2)?)?;
+    envelope.payload = r#"{"command":"Status"}"#.to_string();
+    assert!(
+        receiver.open_at(&to_string(&envelope)?, NOW).is_err(),
+        "tampered payload"
+    );
+
+    assert!(
+        receiver.open_at(r#"{"command":"Status"}"#, NOW).is_err(),
+        "unsigned"
+    );
+
+    Ok(())
+}
@@ -0,0 +1,41 @@
+use anyhow::Result;
+use chrono::{Duration, Local, TimeZone};
+use grobot::CameraConfig;
+use std::path::PathBuf;
+use toml::from_str;
+
+#[test]
+fn test_camera_config() -> Result<()> {
+    let camera: CameraConfig = from_str(
+        r#"
+        directory = "/var/lib/grobot/timelapse"
+        interval = "15m"
+    "#,
+    )?;
+    camera.validate()?;
+
+    assert_eq!(camera.device, PathBuf::from("/dev/video0"));
+    assert_eq!(camera.interval, Duration::minutes(15));
+    assert!(camera.resolution.is_none());
+
+    let time = Local.with_ymd_and_hms(2023, 4, 23, 9, 5, 30).unwrap();
+    assert_eq!(
+        camera.image_path(&time),
+        PathBuf::from("/var/lib/grobot/timelapse/2023-04-23/090530.jpg")
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_camera_zero_interval() -> Result<()> {
+    let camera: CameraConfig = from_str(
+        r#"
+        directory = "/tmp"
+        interval = "0s"
+    "#,
+    )?;
+    assert!(camera.validate().is_err());
+
+    Ok(())
+}
@@ -0,0 +1,18 @@
+use grobot::co2::{mhz19_checksum, sensirion_crc};
+
+#[test]
+fn test_mhz19_checksum() {
+    // Read command from the datasheet
+    let command = [0xff, 0x01, 0x86, 0x00, 0x00, 0x00, 0x00, 0x00, 0x79];
+    assert_eq!(mhz19_checksum(&command), 0x79);
+
+    // Response for 600ppm
+    let response = [0xff, 0x86, 0x02, 0x58, 0x00, 0x00, 0x00, 0x00, 0x20];
+    assert_eq!(mhz19_checksum(&response), 0x20);
+}
+
+#[test]
+fn test_sensirion_crc() {
+    // Example from the SCD4x datasheet
+    assert_eq!(sensirion_crc(&[0xbe, 0xef]), 0x92);
+}
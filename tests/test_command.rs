@@ -0,0 +1,51 @@
+use anyhow::Result;
+use chrono::{Duration, Local, TimeZone};
+use grobot::{Actuator, Command, Override};
+use serde_json::to_string;
+
+#[test]
+fn test_command_round_trip() -> Result<()> {
+    let command = Command::Override {
+        actuator: Actuator::Mist,
+        on: true,
+        seconds: Some(300),
+    };
+
+    assert_eq!(Command::parse(&to_string(&command)?)?, command);
+    assert_eq!(
+        Command::parse(r#"{"command": "Auto", "actuator": "Fan"}"#)?,
+        Command::Auto {
+            actuator: Actuator::Fan
+        }
+    );
+    assert_eq!(Command::parse(r#"{"command": "Status"}"#)?, Command::Status);
+    assert!(Command::parse(r#"{"command": "Reboot"}"#).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_override() -> Result<()> {
+    let now = Local.with_ymd_and_hms(2023, 4, 23, 8, 0, 0).unwrap();
+
+    let held = Override::new(true, Some(300), &now)?;
+    assert!(held.active(&(now + Duration::minutes(4))));
+    assert!(!held.active(&(now + Duration::minutes(5))));
+
+    let held = Override::new(false, None, &now)?;
+    assert!(held.active(&(now + Duration::days(30))));
+
+    assert!(Override::new(true, Some(10u64.pow(13)), &now).is_err());
+    assert!(Override::new(true, Some(u64::MAX), &now).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_parse_actuator() -> Result<()> {
+    assert_eq!("mist".parse::<Actuator>()?, Actuator::Mist);
+    assert_eq!("Light".parse::<Actuator>()?, Actuator::Light);
+    assert!("pump".parse::<Actuator>().is_err());
+
+    Ok(())
+}
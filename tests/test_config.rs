@@ -1,6 +1,6 @@
 use anyhow::Result;
-use chrono::{DateTime, Local, NaiveDateTime, TimeZone};
-use grobot::Config;
+use chrono::{DateTime, Duration, Local, NaiveDate, NaiveDateTime, NaiveTime, TimeZone};
+use grobot::{parse_duration, Actuator, Config, DoorState, Location, SwitchLevel, TimeSpec};
 use toml::from_str;
 
 const CONFIG: &str = include_str!("../configs/default.toml");
@@ -17,6 +17,7 @@ fn test_parse_config() -> Result<()> {
 #[test]
 fn test_config_times() -> Result<()> {
     let mut default_config: Config = from_str(CONFIG)?;
+    default_config.setup()?;
 
     let time_801am_april_23_2023 = "2023-04-23 08:01";
     let parsed_time = NaiveDateTime::parse_from_str(time_801am_april_23_2023, "%Y-%m-%d %H:%M")?;
@@ -31,18 +32,488 @@ fn test_config_times() -> Result<()> {
         "light expected on at 8am"
     );
 
-    let time_1230pm_april_23_2023 = "2023-04-23 12:30";
-    let parsed_time = NaiveDateTime::parse_from_str(time_1230pm_april_23_2023, "%Y-%m-%d %H:%M")?;
+    let time_1215pm_april_23_2023 = "2023-04-23 12:15";
+    let parsed_time = NaiveDateTime::parse_from_str(time_1215pm_april_23_2023, "%Y-%m-%d %H:%M")?;
     let local = Local.from_local_datetime(&parsed_time).unwrap();
 
     assert!(
         default_config.fan_off(&local, (NOMINAL_TEMP, NOMINAL_HUMIDITY)),
-        "fan expected off at 1230pm"
+        "fan expected off at 1215pm"
     );
     assert!(
         default_config.light_off(&local, (NOMINAL_TEMP, NOMINAL_HUMIDITY)),
-        "light expected off at 1230pm"
+        "light expected off at 1215pm"
     );
 
     Ok(())
 }
+
+const SOLAR_CONFIG: &str = r#"
+[location]
+latitude = 40.7128
+longitude = -74.0060
+
+[thresholds]
+min_humidity = 30.0
+max_humidity = 95.0
+min_temp = 62.0
+max_temp = 86.0
+
+[fan]
+power = 75.0
+schedule = [
+    { time = "00:00", action = "On" },
+    { time = "00:10", action = "Off" },
+]
+
+[light]
+schedule = [
+    { time = "sunrise+30m", action = "On" },
+    { time = "sunset-1h", action = "Off" },
+]
+
+[mist]
+schedule = [
+    { time = "07:00", action = "On" },
+    { time = "07:08", action = "Off" },
+]
+"#;
+
+#[test]
+fn test_parse_time_spec() -> Result<()> {
+    assert_eq!(
+        "sunrise+30m".parse::<TimeSpec>()?,
+        TimeSpec::Sunrise(Duration::minutes(30))
+    );
+    assert_eq!(
+        "sunset-1h30m".parse::<TimeSpec>()?,
+        TimeSpec::Sunset(-Duration::minutes(90))
+    );
+    assert_eq!(
+        "sunset".parse::<TimeSpec>()?,
+        TimeSpec::Sunset(Duration::zero())
+    );
+    assert!("sunset*2".parse::<TimeSpec>().is_err());
+    assert!("sunrise+30".parse::<TimeSpec>().is_err());
+    Ok(())
+}
+
+#[test]
+fn test_parse_duration() -> Result<()> {
+    assert_eq!(parse_duration("1h30m")?, Duration::minutes(90));
+    assert_eq!(parse_duration("7d")?, Duration::days(7));
+    assert_eq!(parse_duration("90s")?, Duration::seconds(90));
+    assert!(parse_duration("30").is_err());
+    assert!(parse_duration("3w").is_err());
+    assert!(
+        parse_duration("99999999999999d").is_err(),
+        "too long to represent"
+    );
+    assert!(parse_duration("99999999999999999999999s").is_err());
+    Ok(())
+}
+
+#[test]
+fn test_solar_offset_past_midnight() {
+    let new_york = Location {
+        latitude: 40.7128,
+        longitude: -74.0060,
+    };
+    let date = NaiveDate::from_ymd_opt(2023, 6, 21).unwrap();
+    let (sunrise, sunset) = new_york.sunrise_sunset(date).unwrap();
+    let since_midnight =
+        |time: DateTime<Local>| time.time() - NaiveTime::from_hms_opt(0, 0, 0).unwrap();
+
+    // Offsets reaching an hour past either end of the day, wherever the tests run
+    let late = TimeSpec::Sunset(Duration::hours(25) - since_midnight(sunset.with_timezone(&Local)));
+    let early =
+        TimeSpec::Sunrise(-since_midnight(sunrise.with_timezone(&Local)) - Duration::hours(1));
+
+    assert_eq!(
+        late.resolve(date, Some(&new_york)),
+        NaiveTime::from_hms_opt(23, 59, 59),
+        "stops at the end of the day rather than wrapping to the morning"
+    );
+    assert_eq!(
+        early.resolve(date, Some(&new_york)),
+        NaiveTime::from_hms_opt(0, 0, 0)
+    );
+}
+
+#[test]
+fn test_solar_schedule() -> Result<()> {
+    let mut config: Config = from_str(SOLAR_CONFIG)?;
+    config.setup()?;
+
+    let location = Location {
+        latitude: 40.7128,
+        longitude: -74.0060,
+    };
+    let date = NaiveDate::from_ymd_opt(2023, 6, 21).unwrap();
+    let (sunrise, sunset) = location.sunrise_sunset(date).unwrap();
+    let sunrise = sunrise.with_timezone(&Local);
+    let sunset = sunset.with_timezone(&Local);
+
+    let environment = (NOMINAL_TEMP, NOMINAL_HUMIDITY);
+
+    assert!(
+        config.light_off(&(sunrise + Duration::minutes(29)), environment),
+        "light expected off before sunrise+30m"
+    );
+    assert!(
+        config.light_on(&(sunrise + Duration::minutes(31)), environment),
+        "light expected on after sunrise+30m"
+    );
+    assert!(
+        config.light_on(&(sunset - Duration::minutes(61)), environment),
+        "light expected on before sunset-1h"
+    );
+    assert!(
+        config.light_off(&(sunset - Duration::minutes(59)), environment),
+        "light expected off after sunset-1h"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_solar_schedule_needs_location() -> Result<()> {
+    let config = SOLAR_CONFIG.replace("[location]", "[unused]");
+    let mut config: Config = from_str(&config)?;
+    assert!(config.setup().is_err());
+    Ok(())
+}
+
+const WEEKLY_CONFIG: &str = r#"
+[thresholds]
+min_humidity = 30.0
+max_humidity = 95.0
+min_temp = 62.0
+max_temp = 86.0
+
+[fan]
+power = 75.0
+schedule = [
+    { time = "08:00", action = "On", days = ["Mon", "Tue", "Wed", "Thu", "Fri"] },
+    { time = "08:10", action = "Off", days = ["Mon", "Tue", "Wed", "Thu", "Fri"] },
+    { time = "09:00", action = "On", days = ["Sat", "Sun"] },
+    { time = "09:30", action = "Off", days = ["Sat", "Sun"] },
+]
+
+[light]
+schedule = [
+    { time = "06:00", action = "On" },
+    { time = "23:00", action = "Off" },
+]
+
+[mist]
+schedule = [
+    { time = "07:00", action = "On" },
+    { time = "07:08", action = "Off" },
+    { time = "07:00", action = "On", days = ["Sunday"] },
+    { time = "07:30", action = "Off", days = ["Sunday"] },
+]
+"#;
+
+fn local(time: &str) -> Result<DateTime<Local>> {
+    let parsed_time = NaiveDateTime::parse_from_str(time, "%Y-%m-%d %H:%M")?;
+    Ok(Local.from_local_datetime(&parsed_time).unwrap())
+}
+
+#[test]
+fn test_weekly_schedule() -> Result<()> {
+    let mut config: Config = from_str(WEEKLY_CONFIG)?;
+    config.setup()?;
+
+    let environment = (NOMINAL_TEMP, NOMINAL_HUMIDITY);
+
+    // April 23 2023 is a Sunday, April 24 2023 is a Monday
+    assert!(
+        config.fan_off(&local("2023-04-23 08:05")?, environment),
+        "fan expected off at 805am on Sunday"
+    );
+    assert!(
+        config.fan_on(&local("2023-04-23 09:15")?, environment),
+        "fan expected on at 915am on Sunday"
+    );
+    assert!(
+        config.fan_on(&local("2023-04-24 08:05")?, environment),
+        "fan expected on at 805am on Monday"
+    );
+    assert!(
+        config.fan_off(&local("2023-04-24 09:15")?, environment),
+        "fan expected off at 915am on Monday"
+    );
+
+    // The Sunday soak overlaps the daily mist window and extends it
+    assert!(
+        config.mist_on(&local("2023-04-23 07:20")?, environment),
+        "mist expected on at 720am on Sunday"
+    );
+    assert!(
+        config.mist_off(&local("2023-04-24 07:20")?, environment),
+        "mist expected off at 720am on Monday"
+    );
+    assert!(
+        config.mist_on(&local("2023-04-24 07:05")?, environment),
+        "mist expected on at 705am on Monday"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_repeat_schedule() -> Result<()> {
+    let mut config: Config = from_str(CONFIG)?;
+    config.setup()?;
+
+    let date = NaiveDate::from_ymd_opt(2023, 4, 23).unwrap();
+    let windows = config.windows(Actuator::Fan, date);
+
+    assert_eq!(windows.len(), 12, "Expected a fan window every 2 hours");
+
+    for (i, (on, off)) in windows.iter().enumerate() {
+        let hour = 2 * i as u32;
+        assert_eq!(*on, NaiveTime::from_hms_opt(hour, 0, 0).unwrap());
+        assert_eq!(*off, NaiveTime::from_hms_opt(hour, 10, 0).unwrap());
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_repeat_until() -> Result<()> {
+    let config = CONFIG.replace(
+        r#"{ every = "2h", for = "10m", starting = "00:00", until = "23:59" }"#,
+        r#"{ every = "45m", for = "30m", starting = "08:00", until = "09:40" }"#,
+    );
+    let mut config: Config = from_str(&config)?;
+    config.setup()?;
+
+    let date = NaiveDate::from_ymd_opt(2023, 4, 23).unwrap();
+    let time = |h, m| NaiveTime::from_hms_opt(h, m, 0).unwrap();
+
+    assert_eq!(
+        config.windows(Actuator::Fan, date),
+        vec![
+            (time(8, 0), time(8, 30)),
+            (time(8, 45), time(9, 15)),
+            (time(9, 30), time(9, 40)),
+        ]
+    );
+
+    Ok(())
+}
+
+const DIMMING_CONFIG: &str = r#"
+[thresholds]
+min_humidity = 30.0
+max_humidity = 95.0
+min_temp = 62.0
+max_temp = 86.0
+
+[fan]
+power = 75.0
+repeat = [{ every = "2h", for = "10m" }]
+
+[light]
+dimming = { frequency = 1000.0 }
+schedule = [
+    { time = "06:00", action = "On", level = 50.0, fade = "30m" },
+    { time = "08:00", action = "Off" },
+    { time = "08:00", action = "On", level = 100.0, fade = "10m" },
+    { time = "12:00", action = "Off", fade = "1h" },
+]
+
+[mist]
+schedule = [
+    { time = "07:00", action = "On" },
+    { time = "07:08", action = "Off" },
+]
+"#;
+
+#[test]
+fn test_light_level() -> Result<()> {
+    let mut config: Config = from_str(DIMMING_CONFIG)?;
+    config.setup()?;
+
+    let environment = (NOMINAL_TEMP, NOMINAL_HUMIDITY);
+    let level = |config: &mut Config, time| -> Result<f64> {
+        Ok(config.light_level(&local(time)?, environment))
+    };
+
+    assert!(config.light_dimming().is_some());
+    assert_eq!(level(&mut config, "2023-04-23 05:59")?, 0.0);
+    assert_eq!(level(&mut config, "2023-04-23 06:00")?, 0.0);
+    assert_eq!(level(&mut config, "2023-04-23 06:15")?, 25.0);
+    assert_eq!(level(&mut config, "2023-04-23 07:00")?, 50.0);
+    assert_eq!(level(&mut config, "2023-04-23 08:05")?, 75.0);
+    assert_eq!(level(&mut config, "2023-04-23 10:00")?, 100.0);
+    assert_eq!(level(&mut config, "2023-04-23 11:30")?, 50.0);
+    assert_eq!(level(&mut config, "2023-04-23 12:00")?, 0.0);
+
+    // Too cold, the light runs at full power to warm the cabinet
+    assert_eq!(
+        config.light_level(&local("2023-04-23 14:00")?, (50.0, NOMINAL_HUMIDITY)),
+        100.0
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_light_dli_compensation() -> Result<()> {
+    let config = CONFIG.replace(
+        "[light]\n",
+        "[light]\ndli = { ppfd = 250.0, target = 15.0, until = \"23:30\" }\n",
+    );
+    let mut config: Config = from_str(&config)?;
+    config.setup()?;
+
+    let environment = (NOMINAL_TEMP, NOMINAL_HUMIDITY);
+
+    assert!(
+        !config.light_compensate(&local("2023-04-23 22:00")?, environment, 10.0),
+        "light should not be extended before the end of the schedule"
+    );
+    assert!(
+        config.light_compensate(&local("2023-04-23 23:10")?, environment, 10.0),
+        "light should be extended to reach the target DLI"
+    );
+    assert!(
+        !config.light_compensate(&local("2023-04-23 23:10")?, environment, 15.0),
+        "light should not be extended once the target DLI is reached"
+    );
+    assert!(
+        !config.light_compensate(&local("2023-04-23 23:45")?, environment, 10.0),
+        "light should not be extended past the until time"
+    );
+    assert!(
+        !config.light_compensate(&local("2023-04-23 23:10")?, (90.0, NOMINAL_HUMIDITY), 10.0),
+        "light should not be extended when it is too hot"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_fan_stalled() -> Result<()> {
+    let config = CONFIG.replace(
+        "power = 75.0\n",
+        "power = 75.0\ntach_pins = [17, 27]\nstall_threshold = 30.0\n",
+    );
+    let mut config: Config = from_str(&config)?;
+    config.setup()?;
+
+    assert_eq!(config.fan_tach_pins(), &[17, 27]);
+    assert!(
+        config.fan_stalled(0.75, 0.0),
+        "fan at 75% and 0 RPM is stalled"
+    );
+    assert!(
+        !config.fan_stalled(0.75, 1200.0),
+        "fan at 75% and 1200 RPM is fine"
+    );
+    assert!(!config.fan_stalled(0.25, 0.0), "fan at 25% may not spin");
+    assert!(
+        !config.fan_stalled(0.0, 0.0),
+        "fan that is off is not stalled"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_next_transition() -> Result<()> {
+    let mut config: Config = from_str(WEEKLY_CONFIG)?;
+    config.setup()?;
+
+    // April 23 2023 is a Sunday, April 24 2023 is a Monday
+    assert_eq!(
+        config.next_transition(&local("2023-04-23 06:30")?),
+        Some(local("2023-04-23 07:00")?)
+    );
+    assert_eq!(
+        config.next_transition(&local("2023-04-23 07:00")?),
+        Some(local("2023-04-23 07:08")?),
+        "a transition is only next if it is after the time"
+    );
+    assert_eq!(
+        config.next_transition(&local("2023-04-23 07:10")?),
+        Some(local("2023-04-23 07:30")?),
+        "the Sunday mist window runs longer"
+    );
+    assert_eq!(
+        config.next_transition(&local("2023-04-23 23:30")?),
+        Some(local("2023-04-24 06:00")?),
+        "the next transition can be tomorrow"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_fan_min_co2() -> Result<()> {
+    let config = CONFIG.replace("max_temp = 86.0\n", "max_temp = 86.0\nmin_co2 = 400.0\n");
+    let mut config: Config = from_str(&config)?;
+    config.setup()?;
+
+    // Between the repeating fan windows
+    let time = local("2023-04-23 01:00")?;
+    let environment = (NOMINAL_TEMP, NOMINAL_HUMIDITY);
+
+    assert!(!config.fan_on(&time, environment));
+    assert!(!config.fan_on_with_co2(&time, environment, Some(800.0)));
+    assert!(
+        config.fan_on_with_co2(&time, environment, Some(350.0)),
+        "fan should exchange air when CO2 is low"
+    );
+    assert!(
+        config.fan_on_with_co2(&time, (50.0, NOMINAL_HUMIDITY), Some(350.0)),
+        "low CO2 overrides the fan being off for the cold"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_mist_reservoir() -> Result<()> {
+    let mut config: Config = from_str(CONFIG)?;
+    assert!(config.mist_reservoir().is_none());
+
+    let with_reservoir = CONFIG.replace("[mist]\n", "[mist]\nreservoir = { pin = 20 }\n");
+    config = from_str(&with_reservoir)?;
+    let reservoir = config.mist_reservoir().unwrap();
+    assert_eq!(reservoir.pin, 20);
+    assert_eq!(reservoir.active, SwitchLevel::Low);
+
+    Ok(())
+}
+
+#[test]
+fn test_door() -> Result<()> {
+    let config = format!(
+        "{}\n[door]\npin = 16\nactive = \"High\"\npause = [\"Mist\", \"Fan\"]\nsettle = \"5m\"\n",
+        CONFIG
+    );
+    let mut config: Config = from_str(&config)?;
+    config.setup()?;
+
+    assert_eq!(config.door_switch().unwrap().active, SwitchLevel::High);
+    assert!(config.door_pauses(Actuator::Mist));
+    assert!(config.door_pauses(Actuator::Fan));
+    assert!(!config.door_pauses(Actuator::Light));
+
+    let mut door = DoorState::default();
+    let settle = config.door_settle();
+    let now = local("2023-04-23 08:01")?;
+
+    assert!(!door.suspended(settle, &now));
+    door.open();
+    assert!(door.suspended(settle, &now));
+    door.close(now);
+    assert!(door.suspended(settle, &(now + Duration::minutes(4))));
+    assert!(!door.suspended(settle, &(now + Duration::minutes(5))));
+
+    Ok(())
+}
@@ -0,0 +1,46 @@
+use anyhow::Result;
+use chrono::{Duration, Local, TimeZone};
+use grobot::DliTracker;
+
+#[test]
+fn test_dli_integration() -> Result<()> {
+    let mut tracker = DliTracker::default();
+    let start = Local.with_ymd_and_hms(2023, 4, 23, 6, 0, 0).unwrap();
+
+    // 500 umol/m^2/s for 10 hours is 18 mol/m^2/day
+    tracker.update(start, 500.0);
+    tracker.update(start + Duration::hours(4), 500.0);
+    tracker.update(start + Duration::hours(10), 0.0);
+    assert!((tracker.dli() - 18.0).abs() < 1e-9);
+
+    // Dark for the rest of the day adds nothing
+    tracker.update(start + Duration::hours(12), 0.0);
+    assert!((tracker.dli() - 18.0).abs() < 1e-9);
+
+    // And it starts over the next day
+    tracker.update(start + Duration::hours(24), 500.0);
+    assert_eq!(tracker.dli(), 0.0);
+
+    Ok(())
+}
+
+#[test]
+fn test_dli_resume() -> Result<()> {
+    let mut tracker = DliTracker::default();
+    let start = Local.with_ymd_and_hms(2023, 4, 23, 6, 0, 0).unwrap();
+
+    // 250 umol/m^2/s for an hour is 0.9 mol/m^2/day
+    tracker.update(start, 250.0);
+    tracker.update(start + Duration::hours(1), 250.0);
+    assert!((tracker.dli() - 0.9).abs() < 1e-9);
+
+    // Down for six hours, which isn't light the plants got
+    tracker.resume();
+    tracker.update(start + Duration::hours(7), 250.0);
+    assert!((tracker.dli() - 0.9).abs() < 1e-9);
+
+    tracker.update(start + Duration::hours(8), 0.0);
+    assert!((tracker.dli() - 1.8).abs() < 1e-9);
+
+    Ok(())
+}
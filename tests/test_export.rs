@@ -0,0 +1,129 @@
+use anyhow::Result;
+use chrono::{Duration, Local, TimeZone};
+use grobot::{Actuator, Config, Export, History, HistoryConfig};
+use serde_json::{json, Value};
+use std::path::Path;
+use toml::from_str;
+
+const CONFIG: &str = include_str!("../configs/default.toml");
+
+fn history() -> Result<History> {
+    let history = History::open_at(&HistoryConfig::default(), Path::new(":memory:"))?;
+    let start = Local.with_ymd_and_hms(2023, 4, 23, 6, 0, 0).unwrap();
+
+    // The thresholds from when the controller started, with the top temperature raised later
+    let config: Config = from_str(CONFIG)?;
+    history.record_thresholds(&(start - Duration::days(1)), config.thresholds())?;
+    history.record(&(start + Duration::minutes(90)), "threshold/max_temp", 90.0)?;
+
+    history.record(&start, "temperature", 70.0)?;
+    history.record(&start, "humidity", 50.0)?;
+    history.record(&(start + Duration::minutes(30)), "temperature", 74.0)?;
+    history.record(&(start + Duration::minutes(90)), "temperature", 80.0)?;
+
+    // The mister was on before the range, then off for the second half of the first hour
+    history.record_transition(&(start - Duration::hours(1)), Actuator::Mist, true)?;
+    history.record_transition(&(start + Duration::minutes(30)), Actuator::Mist, false)?;
+
+    // The light came on a quarter of the way into the first hour and went off a quarter of
+    // the way into the second
+    history.record_transition(&(start + Duration::minutes(15)), Actuator::Light, true)?;
+    history.record_transition(&(start + Duration::minutes(75)), Actuator::Light, false)?;
+
+    Ok(history)
+}
+
+#[test]
+fn test_export_aggregated() -> Result<()> {
+    let start = Local.with_ymd_and_hms(2023, 4, 23, 6, 0, 0).unwrap();
+    let export = Export::new(
+        &history()?,
+        &start,
+        &(start + Duration::hours(2)),
+        Some(Duration::hours(1)),
+    )?;
+
+    assert_eq!(
+        export.columns(),
+        [
+            "time",
+            "humidity",
+            "temperature",
+            "light_on",
+            "mist_on",
+            "fan_on",
+            "max_humidity",
+            "max_temp",
+            "min_humidity",
+            "min_temp"
+        ]
+    );
+
+    let rows = export.rows();
+    assert_eq!(rows.len(), 2);
+    assert_eq!(rows[0]["temperature"], json!(72.0));
+    assert_eq!(rows[0]["humidity"], json!(50.0));
+    assert_eq!(rows[0]["mist_on"], json!(0.5));
+    assert_eq!(rows[0]["light_on"], json!(0.75));
+    assert_eq!(
+        rows[0]["fan_on"],
+        Value::Null,
+        "nothing known about the fan"
+    );
+    assert_eq!(rows[1]["temperature"], json!(80.0));
+    assert_eq!(rows[1]["mist_on"], json!(0.0));
+    assert_eq!(rows[1]["light_on"], json!(0.25));
+    assert!(rows[1].get("humidity").is_none());
+    assert_eq!(rows[0]["max_temp"], json!(86.0));
+    assert_eq!(rows[0]["min_humidity"], json!(30.0));
+    assert_eq!(rows[1]["max_temp"], json!(90.0), "raised during the window");
+
+    let mut csv = Vec::new();
+    export.write_csv(&mut csv)?;
+    let csv = String::from_utf8(csv)?;
+    let mut lines = csv.lines();
+    assert_eq!(
+        lines.next().unwrap(),
+        "time,humidity,temperature,light_on,mist_on,fan_on,max_humidity,max_temp,min_humidity,min_temp"
+    );
+    assert!(lines
+        .next()
+        .unwrap()
+        .ends_with(",50.0,72.0,0.75,0.5,,95.0,86.0,30.0,62.0"));
+    assert_eq!(lines.count(), 1);
+
+    Ok(())
+}
+
+#[test]
+fn test_export_raw() -> Result<()> {
+    let start = Local.with_ymd_and_hms(2023, 4, 23, 6, 0, 0).unwrap();
+    let export = Export::new(&history()?, &start, &(start + Duration::hours(2)), None)?;
+
+    let rows = export.rows();
+    assert_eq!(rows.len(), 3, "a row for each time readings were taken");
+    assert_eq!(rows[0]["mist_on"], json!(1.0));
+    assert_eq!(
+        rows[0]["light_on"],
+        Value::Null,
+        "nothing known about the light before it came on"
+    );
+    assert_eq!(rows[1]["light_on"], json!(1.0));
+    assert_eq!(rows[1]["max_temp"], json!(86.0));
+    assert_eq!(rows[2]["max_temp"], json!(90.0), "raised as this was read");
+    assert_eq!(rows[1]["temperature"], json!(74.0));
+    assert_eq!(
+        rows[1]["mist_on"],
+        json!(0.0),
+        "switched off as this was read"
+    );
+
+    let mut json_lines = Vec::new();
+    export.write_json_lines(&mut json_lines)?;
+    let first: Value =
+        serde_json::from_str(String::from_utf8(json_lines)?.lines().next().unwrap())?;
+    assert_eq!(first["time"], json!(start.to_rfc3339()));
+    assert_eq!(first["temperature"], json!(70.0));
+
+    Ok(())
+}
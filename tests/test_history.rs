@@ -0,0 +1,161 @@
+use anyhow::Result;
+use chrono::{Duration, Local, TimeZone};
+use grobot::{Actuator, Alarm, Config, History, HistoryConfig, Sample};
+use std::path::Path;
+use toml::from_str;
+
+const CONFIG: &str = include_str!("../configs/default.toml");
+
+fn history() -> Result<History> {
+    History::open_at(&HistoryConfig::default(), Path::new(":memory:"))
+}
+
+#[test]
+fn test_history_config() -> Result<()> {
+    let mut config: Config = from_str(CONFIG)?;
+    config.setup()?;
+    assert_eq!(config.history().raw_retention, Duration::days(7));
+
+    let mut config: Config = from_str(&format!(
+        "{}\n[history]\npath = \"/tmp/history.db\"\nraw_retention = \"2d\"\naverage_interval = \"15m\"\n",
+        CONFIG
+    ))?;
+    config.setup()?;
+    assert_eq!(config.history().raw_retention, Duration::days(2));
+    assert_eq!(config.history().average_interval, Duration::minutes(15));
+    assert_eq!(config.history().average_retention, Duration::days(365));
+
+    let mut config: Config = from_str(&format!(
+        "{}\n[history]\nraw_retention = \"30d\"\naverage_retention = \"7d\"\n",
+        CONFIG
+    ))?;
+    assert!(
+        config.setup().is_err(),
+        "averages can't be dropped before the readings they're made from"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_history_samples() -> Result<()> {
+    let history = history()?;
+    let now = Local.with_ymd_and_hms(2023, 4, 23, 12, 0, 0).unwrap();
+
+    history.record(&(now - Duration::minutes(2)), "temperature", 72.0)?;
+    history.record(&(now - Duration::minutes(1)), "temperature", 73.0)?;
+    history.record(&now, "soil/basil", 40.0)?;
+
+    assert_eq!(history.metrics()?, vec!["soil/basil", "temperature"]);
+    assert_eq!(
+        history.samples("temperature", &(now - Duration::hours(1)), &now)?,
+        vec![
+            Sample {
+                time: now - Duration::minutes(2),
+                value: 72.0
+            },
+            Sample {
+                time: now - Duration::minutes(1),
+                value: 73.0
+            },
+        ]
+    );
+    assert!(history
+        .samples("temperature", &(now - Duration::seconds(30)), &now)?
+        .is_empty());
+
+    Ok(())
+}
+
+#[test]
+fn test_history_prune() -> Result<()> {
+    let history = history()?;
+    let now = Local.with_ymd_and_hms(2023, 4, 23, 12, 0, 0).unwrap();
+    let old = now - Duration::days(8);
+
+    // Two readings in one five minute window past the raw retention, one in the next
+    history.record(&old, "temperature", 70.0)?;
+    history.record(&(old + Duration::minutes(1)), "temperature", 74.0)?;
+    history.record(&(old + Duration::minutes(5)), "temperature", 80.0)?;
+    history.record(&now, "temperature", 75.0)?;
+    // Past the average retention too
+    history.record(&(now - Duration::days(400)), "temperature", 60.0)?;
+    history.record_transition(&(now - Duration::days(400)), Actuator::Mist, true)?;
+
+    history.prune(&now)?;
+
+    let samples = history.samples(
+        "temperature",
+        &(now - Duration::days(500)),
+        &(now + Duration::seconds(1)),
+    )?;
+    let values: Vec<f64> = samples.iter().map(|sample| sample.value).collect();
+    assert_eq!(values, vec![72.0, 80.0, 75.0]);
+    assert_eq!(samples[2].time, now, "recent readings are kept as they are");
+
+    // Pruning again changes nothing
+    history.prune(&now)?;
+    assert_eq!(
+        history
+            .samples(
+                "temperature",
+                &(now - Duration::days(500)),
+                &(now + Duration::seconds(1))
+            )?
+            .len(),
+        3
+    );
+    assert!(history
+        .transitions(&(now - Duration::days(500)), &now)?
+        .is_empty());
+
+    Ok(())
+}
+
+#[test]
+fn test_history_events() -> Result<()> {
+    let history = history()?;
+    let now = Local.with_ymd_and_hms(2023, 4, 23, 12, 0, 0).unwrap();
+
+    history.record_transition(&(now - Duration::minutes(5)), Actuator::Fan, true)?;
+    history.record_transition(&now, Actuator::Fan, false)?;
+    history.record_alarm(&now, &Alarm::FanStalled { fan: 1 }, true)?;
+
+    let transitions =
+        history.transitions(&(now - Duration::hours(1)), &(now + Duration::seconds(1)))?;
+    assert_eq!(transitions.len(), 2);
+    assert_eq!(transitions[0].actuator, Actuator::Fan);
+    assert!(transitions[0].on);
+    assert!(!transitions[1].on);
+
+    let alarms = history.alarms(&(now - Duration::hours(1)), &(now + Duration::seconds(1)))?;
+    assert_eq!(alarms.len(), 1);
+    assert_eq!(alarms[0].alarm, Alarm::FanStalled { fan: 1 });
+    assert!(alarms[0].raised);
+
+    Ok(())
+}
+
+#[test]
+fn test_history_read_only() -> Result<()> {
+    let path = std::env::temp_dir().join(format!("grobot-history-{}.db", std::process::id()));
+    let config = HistoryConfig::default();
+    let time = Local.with_ymd_and_hms(2023, 4, 23, 6, 0, 0).unwrap();
+
+    assert!(
+        History::open_read_only(&config, &path).is_err(),
+        "a missing database isn't created"
+    );
+
+    History::open_at(&config, &path)?.record(&time, "temperature", 70.0)?;
+    let history = History::open_read_only(&config, &path)?;
+    assert_eq!(history.metrics()?, ["temperature"]);
+    assert!(history.record(&time, "temperature", 71.0).is_err());
+
+    drop(history);
+    for suffix in ["", "-wal", "-shm"] {
+        let _ = std::fs::remove_file(format!("{}{}", path.display(), suffix));
+    }
+
+    Ok(())
+}
@@ -0,0 +1,52 @@
+use chrono::{Duration, Local};
+use grobot::{Actuator, Metrics};
+
+#[test]
+fn test_metrics_switched() {
+    let start = Local::now();
+    let mut metrics = Metrics::default();
+
+    metrics.switched(Actuator::Mist, true, start);
+    metrics.switched(Actuator::Mist, true, start + Duration::seconds(5));
+    assert!(metrics.is_on(Actuator::Mist));
+    assert_eq!(
+        metrics.on_seconds(Actuator::Mist, start + Duration::seconds(10)),
+        10.0,
+        "counts the time it has been on so far"
+    );
+
+    metrics.switched(Actuator::Mist, false, start + Duration::seconds(30));
+    metrics.switched(Actuator::Mist, true, start + Duration::seconds(60));
+    metrics.switched(Actuator::Mist, false, start + Duration::seconds(90));
+    assert!(!metrics.is_on(Actuator::Mist));
+    assert_eq!(
+        metrics.on_seconds(Actuator::Mist, start + Duration::seconds(120)),
+        60.0
+    );
+
+    let text = metrics.render(None, start + Duration::seconds(120));
+    assert!(text.contains("grobot_actuator_toggles_total{actuator=\"mist\"} 4\n"));
+    assert!(text.contains("grobot_actuator_on_seconds_total{actuator=\"mist\"} 60\n"));
+    assert!(text.contains("grobot_actuator_on{actuator=\"fan\"} 0\n"));
+}
+
+#[test]
+fn test_metrics_render() {
+    let mut metrics = Metrics::default();
+    metrics.reading(75.5, 60.0);
+    metrics.read_failed("climate");
+    metrics.read_failed("climate");
+    metrics.read_failed("co2");
+
+    let text = metrics.render(None, Local::now());
+    assert!(text.contains("# TYPE grobot_raw_temperature_fahrenheit gauge\n"));
+    assert!(text.contains("grobot_raw_temperature_fahrenheit 75.5\n"));
+    assert!(text.contains("grobot_raw_humidity_percent 60\n"));
+    assert!(text.contains("# TYPE grobot_sensor_read_failures_total counter\n"));
+    assert!(text.contains("grobot_sensor_read_failures_total{sensor=\"climate\"} 2\n"));
+    assert!(text.contains("grobot_sensor_read_failures_total{sensor=\"co2\"} 1\n"));
+    assert!(
+        !text.contains("grobot_temperature_fahrenheit"),
+        "no filtered readings without a status update"
+    );
+}
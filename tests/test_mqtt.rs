@@ -0,0 +1,130 @@
+use anyhow::Result;
+use grobot::{Actuator, Command, Config, StreamEvent, Topics};
+use serde_json::Value;
+use toml::from_str;
+
+const CONFIG: &str = include_str!("../configs/default.toml");
+
+fn config(mqtt: &str) -> Result<Config> {
+    let mut config: Config = from_str(&format!(
+        "{}\n[soil]\nsensors = [{{ name = \"Sweet Basil\", channel = 0, dry = 17000, wet = 7000 }}]\n\n[mqtt]\n{}\n",
+        CONFIG, mqtt
+    ))?;
+    config.setup()?;
+    Ok(config)
+}
+
+fn topics(config: &Config) -> Topics {
+    Topics::new(config.mqtt().unwrap(), "grobot.local")
+}
+
+#[test]
+fn test_mqtt_config() -> Result<()> {
+    let mqtt = config("host = \"localhost\"")?.mqtt().unwrap().clone();
+    assert_eq!(mqtt.port, 1883);
+    assert_eq!(mqtt.prefix, "grobot");
+    assert_eq!(mqtt.discovery_prefix, "homeassistant");
+
+    assert!(config("host = \"localhost\"\nusername = \"grobot\"").is_err());
+    assert!(config("host = \"localhost\"\nprefix = \"grobot/#\"").is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_mqtt_commands() -> Result<()> {
+    let topics = topics(&config("host = \"localhost\"")?);
+    assert_eq!(
+        topics.command(Actuator::Mist),
+        "grobot/grobot_local/mist/set"
+    );
+    assert_eq!(topics.commands(), "grobot/grobot_local/+/set");
+
+    assert_eq!(
+        topics.parse_command("grobot/grobot_local/mist/set", b"ON")?,
+        Command::Override {
+            actuator: Actuator::Mist,
+            on: true,
+            seconds: None
+        }
+    );
+    assert_eq!(
+        topics.parse_command("grobot/grobot_local/fan/set", b"auto\n")?,
+        Command::Auto {
+            actuator: Actuator::Fan
+        }
+    );
+    assert!(topics
+        .parse_command("grobot/grobot_local/mist/set", b"TOGGLE")
+        .is_err());
+    assert!(topics
+        .parse_command("grobot/grobot_local/pump/set", b"ON")
+        .is_err());
+    assert!(topics
+        .parse_command("grobot/elsewhere/mist/set", b"ON")
+        .is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_mqtt_publications() -> Result<()> {
+    let topics = topics(&config("host = \"localhost\"")?);
+
+    let published = topics.publications(&StreamEvent::Actuator {
+        actuator: Actuator::Fan,
+        on: true,
+    });
+    assert_eq!(
+        published,
+        vec![(
+            "grobot/grobot_local/fan/state".to_string(),
+            "ON".to_string()
+        )]
+    );
+
+    let published = topics.publications(&StreamEvent::Light { level: 0.0 });
+    assert!(published.contains(&(
+        "grobot/grobot_local/light/state".to_string(),
+        "OFF".to_string()
+    )));
+
+    assert!(topics.publications(&StreamEvent::DoorOpened).is_empty());
+
+    Ok(())
+}
+
+#[test]
+fn test_mqtt_discovery() -> Result<()> {
+    let config = config("host = \"localhost\"")?;
+    let discovery = topics(&config).discovery(&config);
+
+    let (_, payload) = discovery
+        .iter()
+        .find(|(topic, _)| topic == "homeassistant/switch/grobot_local/mist/config")
+        .expect("a switch for the mister");
+    let payload: Value = serde_json::from_str(payload)?;
+    assert_eq!(payload["command_topic"], "grobot/grobot_local/mist/set");
+    assert_eq!(payload["state_topic"], "grobot/grobot_local/mist/state");
+    assert_eq!(payload["unique_id"], "grobot_local_mist");
+    assert_eq!(
+        payload["availability_topic"],
+        "grobot/grobot_local/availability"
+    );
+    assert_eq!(payload["device"]["name"], "grobot.local");
+
+    assert!(discovery
+        .iter()
+        .any(|(topic, _)| topic == "homeassistant/sensor/grobot_local/soil_sweet_basil/config"));
+    assert!(
+        !discovery
+            .iter()
+            .any(|(topic, _)| topic.ends_with("/co2/config")),
+        "no CO2 sensor configured"
+    );
+
+    let config = self::config("host = \"localhost\"\ndiscovery_prefix = \"\"")?;
+    assert!(topics(&config).discovery(&config).is_empty());
+
+    Ok(())
+}
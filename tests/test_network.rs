@@ -0,0 +1,79 @@
+use anyhow::Result;
+use grobot::{Config, NetworkUpdate, SensorHealth, UpdateTracker};
+use serde_json::to_string;
+use std::collections::HashMap;
+use toml::from_str;
+
+const CONFIG: &str = include_str!("../configs/default.toml");
+
+fn update(seq: u64, boot_id: u64) -> Result<NetworkUpdate> {
+    let config: Config = from_str(CONFIG)?;
+
+    Ok(NetworkUpdate {
+        version: NetworkUpdate::VERSION,
+        seq,
+        hostname: "grobot".to_string(),
+        boot_id,
+        uptime: 0,
+        temp: Some(72.0),
+        humidity: Some(60.0),
+        co2: None,
+        soil: HashMap::new(),
+        light_level: 100.0,
+        mist_on: false,
+        fan_duty: 0.75,
+        fan_rpm: vec![1200.0],
+        thresholds: config.thresholds().clone(),
+        sensors: SensorHealth {
+            climate: true,
+            ..SensorHealth::default()
+        },
+        alarms: Vec::new(),
+    })
+}
+
+#[test]
+fn test_update_round_trip() -> Result<()> {
+    let update = NetworkUpdate::parse(&to_string(&update(7, 1)?)?)?;
+
+    assert_eq!(update.seq, 7);
+    assert_eq!(update.hostname, "grobot");
+    assert_eq!(update.fan_duty, 0.75);
+    assert!(update.sensors.climate);
+
+    Ok(())
+}
+
+#[test]
+fn test_update_version() -> Result<()> {
+    let mut newer = update(1, 1)?;
+    newer.version = NetworkUpdate::VERSION + 1;
+    assert!(NetworkUpdate::parse(&to_string(&newer)?).is_err());
+
+    // Updates from before the schema was versioned
+    assert!(NetworkUpdate::parse(r#"{"temp": 72.0, "humidity": 60.0}"#).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_update_tracker() -> Result<()> {
+    let mut tracker = UpdateTracker::default();
+    let (booted, rebooted) = (0x5eed, 0x0dd);
+
+    assert!(tracker.accept(&update(1, booted)?));
+    assert!(tracker.accept(&update(2, booted)?));
+    assert!(!tracker.accept(&update(2, booted)?), "duplicate");
+    assert!(!tracker.accept(&update(1, booted)?), "out of order");
+    assert!(
+        tracker.accept(&update(1, rebooted)?),
+        "controller restarted, whatever its clock says"
+    );
+    assert!(tracker.accept(&update(2, rebooted)?));
+    assert!(
+        !tracker.accept(&update(3, booted)?),
+        "from before the restart"
+    );
+
+    Ok(())
+}
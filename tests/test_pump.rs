@@ -0,0 +1,116 @@
+use anyhow::Result;
+use chrono::{DateTime, Duration, Local, NaiveDate, NaiveDateTime, TimeZone};
+use grobot::{Config, PumpLedger};
+use std::collections::HashMap;
+use toml::from_str;
+
+const CONFIG: &str = include_str!("../configs/default.toml");
+
+const PUMP: &str = r#"
+[pump]
+pin = 20
+flow_rate = 2.0
+max_daily = 100.0
+schedule = [
+    { time = "08:00", volume = 50.0 },
+    { time = "20:00", volume = 30.0 },
+]
+rules = [
+    { pot = "basil", below = 30.0, volume = 40.0, cooldown = "6h" },
+    { pot = "mint", below = 40.0, volume = 20.0 },
+]
+"#;
+
+fn local(time: &str) -> Result<DateTime<Local>> {
+    let parsed_time = NaiveDateTime::parse_from_str(time, "%Y-%m-%d %H:%M")?;
+    Ok(Local.from_local_datetime(&parsed_time).unwrap())
+}
+
+fn config() -> Result<Config> {
+    let mut config: Config = from_str(&format!("{}{}", CONFIG, PUMP))?;
+    config.setup()?;
+    Ok(config)
+}
+
+#[test]
+fn test_pump_doses_due() -> Result<()> {
+    let config = config()?;
+
+    assert_eq!(
+        config.pump_doses_due(&local("2023-04-23 07:59")?, &local("2023-04-23 08:00")?),
+        vec![50.0]
+    );
+    assert!(config
+        .pump_doses_due(&local("2023-04-23 08:00")?, &local("2023-04-23 08:02")?)
+        .is_empty());
+    assert_eq!(
+        config.pump_doses_due(&local("2023-04-22 23:59")?, &local("2023-04-23 21:00")?),
+        vec![50.0, 30.0]
+    );
+
+    let pump = config.pump().unwrap();
+    assert_eq!(pump.run_time(50.0).as_secs_f64(), 25.0);
+
+    Ok(())
+}
+
+#[test]
+fn test_pump_rules() -> Result<()> {
+    let config = config()?;
+    let pump = config.pump().unwrap();
+    let now = local("2023-04-23 12:00")?;
+
+    let dry = HashMap::from([("basil".to_string(), 20.0)]);
+    let wet = HashMap::from([("basil".to_string(), 50.0)]);
+    let mut last_watered = HashMap::new();
+
+    assert!(pump.rule_doses(&wet, &last_watered, &now).is_empty());
+    assert_eq!(
+        pump.rule_doses(&dry, &last_watered, &now),
+        vec![("basil".to_string(), 40.0)]
+    );
+
+    last_watered.insert("basil".to_string(), now);
+    assert!(pump
+        .rule_doses(&dry, &last_watered, &(now + Duration::hours(5)))
+        .is_empty());
+    assert_eq!(
+        pump.rule_doses(&dry, &last_watered, &(now + Duration::hours(6))),
+        vec![("basil".to_string(), 40.0)]
+    );
+
+    // Without a cooldown, the readings still get time to catch up with a watering
+    let dry = HashMap::from([("mint".to_string(), 20.0)]);
+    last_watered.insert("mint".to_string(), now);
+    assert!(pump
+        .rule_doses(&dry, &last_watered, &(now + Duration::minutes(29)))
+        .is_empty());
+    assert_eq!(
+        pump.rule_doses(&dry, &last_watered, &(now + Duration::minutes(30))),
+        vec![("mint".to_string(), 20.0)]
+    );
+
+    let mut config: Config = from_str(&format!(
+        "{}{}",
+        CONFIG,
+        PUMP.replace("cooldown = \"6h\"", "cooldown = \"0s\"")
+    ))?;
+    assert!(config.setup().is_err(), "a cooldown can't be zero");
+
+    Ok(())
+}
+
+#[test]
+fn test_pump_daily_maximum() -> Result<()> {
+    let mut ledger = PumpLedger::default();
+    let today = NaiveDate::from_ymd_opt(2023, 4, 23).unwrap();
+    let tomorrow = today.succ_opt().unwrap();
+
+    assert_eq!(ledger.dispense(today, 60.0, Some(100.0)), 60.0);
+    assert_eq!(ledger.dispense(today, 60.0, Some(100.0)), 40.0);
+    assert_eq!(ledger.dispense(today, 60.0, Some(100.0)), 0.0);
+    assert_eq!(ledger.dispensed(), 100.0);
+    assert_eq!(ledger.dispense(tomorrow, 60.0, Some(100.0)), 60.0);
+
+    Ok(())
+}
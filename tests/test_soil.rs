@@ -0,0 +1,44 @@
+use anyhow::Result;
+use grobot::{Config, SoilSensorConfig};
+use toml::from_str;
+
+const CONFIG: &str = include_str!("../configs/default.toml");
+
+#[test]
+fn test_soil_calibration() -> Result<()> {
+    let sensor = SoilSensorConfig {
+        name: "basil".to_string(),
+        channel: 0,
+        dry: 17000,
+        wet: 7000,
+    };
+
+    assert_eq!(sensor.moisture(17000), 0.0);
+    assert_eq!(sensor.moisture(7000), 100.0);
+    assert_eq!(sensor.moisture(12000), 50.0);
+    // Readings past the calibration points are clamped
+    assert_eq!(sensor.moisture(20000), 0.0);
+    assert_eq!(sensor.moisture(5000), 100.0);
+
+    Ok(())
+}
+
+#[test]
+fn test_soil_config() -> Result<()> {
+    let config = format!(
+        "{}\n[soil]\nsensors = [{{ name = \"basil\", channel = 0, dry = 17000, wet = 7000 }}]\n",
+        CONFIG
+    );
+    let mut config: Config = from_str(&config)?;
+    config.setup()?;
+    assert_eq!(config.soil().unwrap().address, 0x48);
+
+    let config = format!(
+        "{}\n[soil]\nsensors = [{{ name = \"basil\", channel = 4, dry = 17000, wet = 7000 }}]\n",
+        CONFIG
+    );
+    let mut config: Config = from_str(&config)?;
+    assert!(config.setup().is_err(), "ADC only has channels 0-3");
+
+    Ok(())
+}
@@ -0,0 +1,54 @@
+use anyhow::Result;
+use chrono::{Duration, Local, TimeZone};
+use grobot::{Environment, State};
+use std::env::temp_dir;
+
+#[tokio::test]
+async fn test_state_round_trip() -> Result<()> {
+    let mut environment = Environment::default();
+    assert!(!environment.has_readings());
+    environment.restore(&[(21.0, 60.0), (22.0, 62.0)], &[800.0]);
+    assert!(environment.has_readings());
+
+    let state = State {
+        saved_at: Some(Local::now()),
+        readings: environment.readings(),
+        co2: environment.co2_readings(),
+        light_level: 75.0,
+        fan_on: true,
+        ..State::default()
+    };
+
+    let path = temp_dir()
+        .join(format!("grobot-test-{}", std::process::id()))
+        .join("state.json");
+    state.save(&path).await?;
+    let loaded = State::load(&path).await?;
+
+    assert_eq!(loaded.readings, vec![(21.0, 60.0), (22.0, 62.0)]);
+    assert_eq!(loaded.co2, vec![800.0]);
+    assert_eq!(loaded.light_level, 75.0);
+    assert!(loaded.fan_on);
+    assert!(!loaded.mist_on);
+
+    Ok(())
+}
+
+#[test]
+fn test_state_stale() {
+    let saved_at = Local.with_ymd_and_hms(2023, 4, 23, 8, 0, 0).unwrap();
+    let state = State {
+        saved_at: Some(saved_at),
+        readings: vec![(21.0, 60.0)],
+        light_level: 100.0,
+        mist_on: true,
+        ..State::default()
+    };
+
+    assert!(state.is_fresh(&(saved_at + Duration::minutes(5))));
+
+    let state = state.without_stale(&(saved_at + Duration::hours(2)));
+    assert!(state.readings.is_empty());
+    assert_eq!(state.light_level, 0.0);
+    assert!(!state.mist_on);
+}
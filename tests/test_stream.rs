@@ -0,0 +1,60 @@
+use anyhow::Result;
+use grobot::{Actuator, Alarm, Api, Config, Override, StreamEvent};
+use serde_json::{from_str, to_string, Value};
+use tokio::sync::mpsc;
+
+const CONFIG: &str = include_str!("../configs/default.toml");
+
+#[test]
+fn test_stream_event_names() -> Result<()> {
+    let events = [
+        StreamEvent::Environment {
+            temp: 75.0,
+            humidity: 60.0,
+        },
+        StreamEvent::Actuator {
+            actuator: Actuator::Mist,
+            on: true,
+        },
+        StreamEvent::Override {
+            actuator: Actuator::Fan,
+            held: Some(Override {
+                on: false,
+                until: None,
+            }),
+        },
+        StreamEvent::AlarmCleared {
+            alarm: Alarm::ReservoirLow,
+        },
+        StreamEvent::DoorOpened,
+    ];
+
+    for event in events {
+        let json: Value = from_str(&to_string(&event)?)?;
+        assert_eq!(json["event"], event.name(), "name matches the tag");
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_stream_publish() -> Result<()> {
+    let mut config: Config = toml::from_str(CONFIG)?;
+    config.setup()?;
+    let (commands, _) = mpsc::channel(1);
+    let api = Api::new(config, CONFIG, commands);
+
+    // Nobody listening yet, so this one goes nowhere
+    api.publish(StreamEvent::DoorOpened);
+
+    let mut events = api.subscribe();
+    api.publish(StreamEvent::Light { level: 50.0 });
+
+    match events.recv().await? {
+        StreamEvent::Light { level } => assert_eq!(level, 50.0),
+        event => panic!("unexpected event {:?}", event),
+    }
+    assert!(events.try_recv().is_err());
+
+    Ok(())
+}
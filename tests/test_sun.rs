@@ -0,0 +1,50 @@
+use anyhow::Result;
+use chrono::{NaiveDate, TimeZone, Utc};
+use grobot::Location;
+
+#[test]
+fn test_sunrise_sunset_new_york() -> Result<()> {
+    let new_york = Location {
+        latitude: 40.7128,
+        longitude: -74.0060,
+    };
+
+    let date = NaiveDate::from_ymd_opt(2023, 6, 21).unwrap();
+    let (sunrise, sunset) = new_york.sunrise_sunset(date).unwrap();
+
+    // 5:25am and 8:31pm EDT
+    let expected_sunrise = Utc.with_ymd_and_hms(2023, 6, 21, 9, 25, 0).unwrap();
+    let expected_sunset = Utc.with_ymd_and_hms(2023, 6, 22, 0, 31, 0).unwrap();
+
+    assert!(
+        (sunrise - expected_sunrise).num_minutes().abs() <= 2,
+        "Sunrise {} too far from {}",
+        sunrise,
+        expected_sunrise
+    );
+    assert!(
+        (sunset - expected_sunset).num_minutes().abs() <= 2,
+        "Sunset {} too far from {}",
+        sunset,
+        expected_sunset
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_polar_night() -> Result<()> {
+    let longyearbyen = Location {
+        latitude: 78.2232,
+        longitude: 15.6267,
+    };
+
+    let date = NaiveDate::from_ymd_opt(2023, 12, 21).unwrap();
+
+    assert!(
+        longyearbyen.sunrise_sunset(date).is_none(),
+        "Sun should not rise in Svalbard in December"
+    );
+
+    Ok(())
+}